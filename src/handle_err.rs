@@ -1,10 +1,13 @@
 extern crate ansi_term;
 
+use std::fmt;
+
 use LangErr;
 use lexer::LexErr;
 use parser::ParserErr;
-use vm::exec::VMExecError;
 use vm::build::VMBuildError;
+use vm::exec::{VMExecError, CallSite};
+use span::{Span, Source};
 
 pub fn print_err_text(msg: &str) {
   print!("{}", ansi_term::Color::Red.bold().paint(format!("{}", msg)));
@@ -14,245 +17,244 @@ fn print_err_type(t: &LangErr) {
   print_err_text(&format!("{:?}\n", t));
 }
 
-fn print_err(title: &str, err_pos: i32, width: i32, description: &str, query: &str) {
-  let query_vec: Vec<&str> = query.split('\n').collect();
+fn print_err(title: &str, span: Option<Span>, description: &str, sources: &[Source]) {
+  println!("{}", ansi_term::Color::Cyan.bold().paint(title));
 
-  let mut line = 0;
-  for (k, v) in query.chars().enumerate() {
-    if k >= err_pos as usize {
-      break;
-    }
-    if v == '\n' {
-      line += 1;
+  let (span, source) = match span {
+    Some(span) if span.line > 0 => match sources.get(span.file as usize) {
+      Some(source) => (span, source),
+      None => {
+        println!("| {}", ansi_term::Color::Red.bold().paint(description));
+        return;
+      }
+    },
+    _ => {
+      println!("| {}", ansi_term::Color::Red.bold().paint(description));
+      return;
     }
+  };
+
+  if !source.name.is_empty() {
+    println!("--> {}:{}:{}", source.name, span.line, span.col);
+  } else {
+    println!("--> {}:{}", span.line, span.col);
   }
 
-  let pre_query = if line >= 1 { format!("| {}\n", query_vec[line - 1]) } else { format!("") };
-  let query = query_vec[line];
-  let post_query = if line < query_vec.len() - 1  { format!("| {}\n", query_vec[line + 1]) } else { format!("") };
-  print!("{}\n{}| {}\n", ansi_term::Color::Cyan.bold().paint(title), pre_query, query);
+  let line = match source.line(span.line) {
+    Some(line) => line,
+    None => {
+      // built without the source code
+      println!("| {}", ansi_term::Color::Red.bold().paint(description));
+      return;
+    }
+  };
 
-  let mut err_pos = err_pos;
-  for i in 0..line {
-    err_pos -= query_vec[i].len() as i32 + 1;
+  if let Some(pre) = source.line(span.line - 1) {
+    println!("| {}", pre);
   }
- 
-  if err_pos >= 0 {
-    let mut offset = String::from("");
-    for _i in 0..err_pos { offset.push('-') }
-    println!("| {}{}", ansi_term::Color::Red.bold().paint(offset), ansi_term::Color::Red.bold().paint("^".repeat(width as usize)));
+  println!("| {}", line);
+
+  // underline the span, up to the end of its first line
+  let offset = " ".repeat(span.col as usize - 1);
+  let rest = (line.chars().count() + 1).saturating_sub(span.col as usize).max(1);
+  let width = (span.width() as usize).min(rest);
+  println!("| {}{}", ansi_term::Color::Red.bold().paint("-".repeat(span.col as usize - 1)), ansi_term::Color::Red.bold().paint("^".repeat(width)));
+
+  if !description.is_empty() {
+    println!("| {}{}", offset, ansi_term::Color::Red.bold().paint(description));
   }
 
-  if description != "" {
-    let mut offset = String::from("");
-    for _i in 0..err_pos { offset.push(' ') }
-    print!("| {}{}\n", offset, ansi_term::Color::Red.bold().paint(description));
+  if let Some(post) = source.line(span.line + 1) {
+    println!("| {}", post);
   }
+}
 
-  print!("{}", post_query);
+// the name of the variant of an error, `UnknownToken` of `UnknownToken("$", ..)`
+fn variant_name<T: fmt::Debug>(err: &T) -> String {
+  format!("{:?}", err).chars()
+    .take_while(|c| c.is_alphanumeric() || *c == '_')
+    .collect()
 }
 
-fn lexer_err(err: &LexErr, query: &str) {
-  let mut title = "";
-  let mut err_pos = -1; // if -1 then not valid
-  let mut width = 1;
-  let mut description = String::from("");
-
-  match err {
-    &LexErr::MismatchedQuotes(pos) => {
-      title = "Lexer error: MismatchedQuotes";
-      err_pos = pos;
-      description = String::from("mismatched quote");
-    },
-    &LexErr::UnknownToken(ref token, pos) => {
-      title = "Lexer error: UnknownToken";
-      err_pos = pos;
-      description = format!("unknown token \"{}\"", token);
-    },
-    &LexErr::UnknownEscapeSequence(c, pos) => {
-      title = "Lexer error: UnknownEscapeSequence";
-      err_pos = pos - 1;
-      width = 2;
-      description = format!("unknown escape sequence: \"{}\"", c);
-    }
-    _ => {
-      title = "Lexer error!";
-      description = format!("{:?}", err);
-    }
-  }
+// a single error, a LangErr holds all the errors of the parser
+enum StageErr<'a> {
+  Lex(&'a LexErr),
+  Parser(&'a ParserErr),
+  Build(&'a VMBuildError),
+  Exec(&'a VMExecError)
+}
 
-  print_err(title, err_pos, width, &description, query);
+fn stage_errs(err: &LangErr) -> Vec<StageErr<'_>> {
+  match *err {
+    LangErr::LexErr(ref err) => vec![StageErr::Lex(err)],
+    LangErr::ParserErr(ref errs) => errs.iter().map(StageErr::Parser).collect(),
+    LangErr::VMBuildErr(ref err) => vec![StageErr::Build(err)],
+    LangErr::VMExecErr(ref err) => vec![StageErr::Exec(err)]
+  }
 }
 
-fn parser_err(err: &ParserErr, query: &str) {
-  let mut title = "";
-  let mut err_pos = -1;
-  let mut width = 1;
-  let mut description = String::from("");
-
-  match err {
-    &ParserErr::UnexpectedIdentifier(pos) => {
-      title = "Parser error: UnexpectedIdentifier";
-      err_pos = pos;
-      description = format!("unexpected identifier");
-    },
-    &ParserErr::UnexpectedLiteral(pos) => {
-      title = "Parser error: UnexpectedLiteral";
-      err_pos = pos;
-      description = format!("unexpected literal");
-    },
-    &ParserErr::UnexpectedToken(pos, token, ref _allowed_tokens, ref _allowed_literal, _allowed_identifier) => {
-      title = "Parser error: UnexpectedToken";
-      err_pos = pos;
-      description = format!("unexpected token: \"{:#?}\"", token);
-    },
-    &ParserErr::UnexpectedEndOfLine(pos) => {
-      title = "Parser error: UnexpectedEndOfLine";
-      err_pos = pos;
-      description = format!("unexpected end of line");
-    },
-    &ParserErr::ExpectedSemiColon(pos) => {
-      title = "Parser error: ExpectedSemiColon";
-      err_pos = pos;
-      description = format!("expected semicolon");
-    },
-    &ParserErr::ExpectedBraceOpen(pos) => {
-      title = "Parser error: ExpectedBraceOpen";
-      err_pos = pos;
-      description = format!("expected open brace for this expression");
-    },
-    &ParserErr::MismatchedParenthesis(pos) => {
-      title = "Parser error: MismatchedParenthesis";
-      err_pos = pos;
-      description = format!("mismatched parenthesis");
-    },
-    // &ParserErr::ExpectedArrow(pos) => {
-    //   title = "Parser error: ExpectedArrow";
-    //   err_pos = pos;
-    //   description = format!("expected arrow for function expression");
-    // },
-    &ParserErr::ExpectedIdentifier(pos) => {
-      title = "Parser error: ExpectedIdentifier";
-      err_pos = pos;
-      description = format!("expected identifier");
-    }
-    _ => {
-      title = "Parser error!";
-      description = format!("{:?}", err);
-    }
+// the stage of the pipeline that failed, and the variant of its error
+fn err_kind(err: &StageErr) -> (&'static str, String) {
+  match *err {
+    StageErr::Lex(err) => ("LexErr", variant_name(err)),
+    StageErr::Parser(err) => ("ParserErr", variant_name(err)),
+    StageErr::Build(err) => ("VMBuildErr", variant_name(err)),
+    StageErr::Exec(err) => ("VMExecErr", variant_name(err))
   }
+}
 
-  print_err(title, err_pos, width, &description, query);
+fn err_title(err: &StageErr) -> String {
+  let stage = match *err {
+    StageErr::Lex(_) => "Lexer error",
+    StageErr::Parser(_) => "Parser error",
+    StageErr::Build(_) => "VMBuildError",
+    StageErr::Exec(_) => "VMExecError"
+  };
+  format!("{}: {}", stage, err_kind(err).1)
 }
 
-// fn interpreter_err(err: &InterpreterErr, query: &str) {
-//   let mut title = "";
-//   let mut err_pos = -1;
-//   let mut width = 1;
-//   let mut description = String::from("");
-
-//   match err {
-//     &InterpreterErr::ArithmeticErr(ref left, ref right, ref operation, ref pos) => {
-//       title = "Interpreter error: ArithmeticErr";
-//       err_pos = *pos;
-//       description = format!("opereration \"{:?}\" not defined for types: \"{}\", \"{}\"", operation, left, right);
-//     },
-//     &InterpreterErr::IdentifierNotFound(ref identifier, ref pos) => {
-//       title = "Interpreter error: IdentifierNotFound";
-//       err_pos = *pos;
-//       description = format!("identifier \"{}\" not found in this scope", identifier);
-//     },
-//     &InterpreterErr::CastErr(ref type1, ref type2, ref pos) => {
-//       title = "Interpreter error: CastErr";
-//       err_pos = *pos;
-//       description = format!("cannot cast type \"{}\" into \"{}\"", type1, type2);
-//     }
-//     _ => {
-//       title = "Interpreter error!";
-//       description = format!("{:?}", err);
-//     }
-//   }
-
-//   print_err(title, err_pos, width, &description, query);
-// }
-
-fn build_err(err: &VMBuildError, query: &str) {
-  let mut title = "";
-  let mut err_pos = -1;
-  let mut width = 1;
-  let mut description = String::from("");
-
-  match err {
-    &VMBuildError::InvalidExpression(ref desc, ref pos) => {
-      title = "VMBuildError: InvalidExpression";
-      err_pos = *pos;
-      description = format!("invalid expression, {}", desc);
-    },
-    &VMBuildError::UnsupportedOperator(ref token, ref pos) => {
-      title = "VMBuildError: UnsupportedOperator";
-      err_pos = *pos;
-      description = format!("unsupported operator: {:?}", token);
-    },
-    _ => {
-      title = "VMBuildError!";
-      description = format!("{:?}", err);
-    }
+fn err_message(err: &StageErr) -> String {
+  match *err {
+    StageErr::Lex(err) => err.to_string(),
+    StageErr::Parser(err) => err.to_string(),
+    StageErr::Build(err) => err.to_string(),
+    StageErr::Exec(err) => err.to_string()
   }
+}
 
-  print_err(title, err_pos, width, &description, query);
+// build errors carry their spans, debug positions of runtime errors are indices into the span table of the running code
+fn err_span(err: &StageErr, spans: &[Span]) -> Option<Span> {
+  match *err {
+    StageErr::Lex(err) => err.span(),
+    StageErr::Parser(err) => err.span(),
+    StageErr::Build(err) => err.span(),
+    StageErr::Exec(err) => err.pos().and_then(|pos| spans.get(pos as usize).cloned())
+  }
 }
 
-fn exec_err(err: &VMExecError, query: &str) {
-  let mut title = "";
-  let mut err_pos = -1;
-  let mut width = 1;
-  let mut description = String::from("");
-
-  match err {
-    &VMExecError::UnsupportedOperation(ref first, ref second, ref op_code, ref pos) => {
-      title = "VMExecError: UnsupportedOperation";
-      err_pos = *pos;
-      description = format!("operation {:?} not supported for types {:?} and {:?}", op_code, first, second);
-    },
-    &VMExecError::VariableNotDefined(ref identifier, pos) => {
-      title = "VMExecError: VariableNotDefined";
-      err_pos = pos;
-      description = format!("variable {:?} not defined", identifier);
-      width = identifier.len() as i32;
-    },
-    &VMExecError::InvalidCast(ref literal, ref to, pos) => {
-      title = "VMExecError: InvalidCast";
-      err_pos = match pos {
-        Some(pos) => pos,
-        None => 0,
-      };
-      description = format!("invalid cast: {:?} to {}", literal, to);
-    },
-    &VMExecError::ArgumentMismatch(ref func_pars, func_pars_len, args_len, func_abs_pos, pos) => {
-      title = "VMExecError: ArgumentMismatch";
-      err_pos = pos;
-      description = format!("<function ({:?}) at {}> takes {} parameter{}, but {} {} given", func_pars, func_abs_pos, func_pars_len, if func_pars_len == 1 {""} else {"s"}, args_len, if args_len == 1 {"was"} else {"were"});
-    },
-    _ => {
-      title = "VMExecError!";
-      description = format!("{:?}", err);
+// where a debug position is, `file:line` or `line N` if the file has no name
+fn location(pos: Option<i32>, sources: &[Source], spans: &[Span]) -> Option<String> {
+  let span = match pos.and_then(|pos| spans.get(pos as usize)) {
+    Some(span) if span.line > 0 => span,
+    _ => return None
+  };
+  Some(match sources.get(span.file as usize) {
+    Some(source) if !source.name.is_empty() => format!("{}:{}", source.name, span.line),
+    _ => format!("line {}", span.line)
+  })
+}
+
+fn trace_line(name: Option<&str>, func: i32, pos: Option<i32>, sources: &[Source], spans: &[Span]) -> String {
+  let name = match name {
+    Some(name) => name.to_string(),
+    None => format!("<function at {}>", func)
+  };
+  match location(pos, sources, spans) {
+    Some(location) => format!("{} ({})", name, location),
+    None => name
+  }
+}
+
+/// The calls that were in progress when `err` happened, innermost first.
+/// Lines are only known in debug builds.
+pub fn trace_notes(err: &VMExecError, trace: &[CallSite], sources: &[Source], spans: &[Span]) -> Vec<String> {
+  if trace.is_empty() {
+    return Vec::new();
+  }
+
+  let mut lines = vec![format!("at {}", trace_line(trace[0].name.as_deref(), trace[0].func, err.pos(), sources, spans))];
+
+  // a call site is in the function of the frame outside it, or at the top level
+  for (i, site) in trace.iter().enumerate() {
+    lines.push(match trace.get(i + 1) {
+      Some(outer) => format!("called from {}", trace_line(outer.name.as_deref(), outer.func, site.pos, sources, spans)),
+      None => match location(site.pos, sources, spans) {
+        Some(location) => format!("called from {}", location),
+        None => String::from("called from the top level")
+      }
+    });
+  }
+
+  // deep recursion repeats the same line
+  let mut notes = Vec::new();
+  let mut i = 0;
+  while i < lines.len() {
+    let mut repeated = 0;
+    while i + repeated + 1 < lines.len() && lines[i + repeated + 1] == lines[i] {
+      repeated += 1;
+    }
+    notes.push(lines[i].clone());
+    if repeated > 0 {
+      notes.push(format!("... {} more", repeated));
     }
+    i += repeated + 1;
   }
 
-  print_err(title, err_pos, width, &description, query);
+  notes
 }
 
-pub fn handle_err(err: &LangErr, query: &str) {
-  if query == "" {
+/// Prints the error with the code it points to.
+/// `spans` is the span table runtime errors refer to, build errors carry their spans.
+pub fn handle_err(err: &LangErr, sources: &[Source], spans: &[Span]) {
+  if sources.is_empty() {
     print_err_type(err);
     return;
   }
 
-  match err {
-    &LangErr::LexErr(ref err) => lexer_err(err, query),
-    &LangErr::ParserErr(ref err) => parser_err(err, query),
-    // &LangErr::InterpreterErr(ref err) => interpreter_err(err, query),
-    &LangErr::VMBuildErr(ref err) => build_err(err, query),
-    &LangErr::VMExecErr(ref err) => exec_err(err, query)
+  for err in stage_errs(err) {
+    print_err(&err_title(&err), err_span(&err, spans), &err_message(&err), sources);
+  }
+}
+
+fn json_string(s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out += "\\\"",
+      '\\' => out += "\\\\",
+      '\n' => out += "\\n",
+      '\r' => out += "\\r",
+      '\t' => out += "\\t",
+      c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+      c => out.push(c)
+    }
   }
-}
\ No newline at end of file
+  out.push('"');
+  out
+}
+
+fn json_option<T: fmt::Display>(val: Option<T>) -> String {
+  match val {
+    Some(val) => val.to_string(),
+    None => String::from("null")
+  }
+}
+
+/// The error as a single line JSON object, one line for every error when the parser found several:
+/// `{"kind", "message", "file", "line", "column", "length", "notes"}`,
+/// where the location is null when it isn't known.
+pub fn err_json(err: &LangErr, sources: &[Source], spans: &[Span], notes: &[String]) -> String {
+  let notes: Vec<String> = notes.iter().map(|note| json_string(note)).collect();
+
+  let lines: Vec<String> = stage_errs(err).iter().map(|err| {
+    let (stage, kind) = err_kind(err);
+    let span = err_span(err, spans).filter(|span| span.line > 0);
+    let file = span
+      .and_then(|span| sources.get(span.file as usize))
+      .filter(|source| !source.name.is_empty())
+      .map(|source| json_string(&source.name));
+
+    format!(
+      "{{\"kind\": {}, \"message\": {}, \"file\": {}, \"line\": {}, \"column\": {}, \"length\": {}, \"notes\": [{}]}}",
+      json_string(&format!("{}::{}", stage, kind)),
+      json_string(&err_message(err)),
+      json_option(file),
+      json_option(span.map(|span| span.line)),
+      json_option(span.map(|span| span.col)),
+      json_option(span.map(|span| span.width())),
+      notes.join(", ")
+    )
+  }).collect();
+
+  lines.join("\n")
+}
@@ -1,4 +1,7 @@
 use std::collections::HashMap;
+use std::{error, fmt};
+
+use span::Span;
 
 macro_rules! map(
   { $($key:expr => $value:expr),+ } => {
@@ -14,25 +17,62 @@ macro_rules! map(
 
 #[derive(Debug)]
 pub enum LexErr {
-  MismatchedQuotes(i32),
-  UnknownToken(String, i32),
-  UnknownEscapeSequence(char, i32)
+  MismatchedQuotes(Span),
+  UnknownToken(String, Span),
+  UnknownEscapeSequence(char, Span),
+  IntegerOverflow(String, Span)
+}
+
+impl LexErr {
+  pub fn span(&self) -> Option<Span> {
+    match *self {
+      LexErr::MismatchedQuotes(span) |
+      LexErr::UnknownToken(_, span) |
+      LexErr::UnknownEscapeSequence(_, span) |
+      LexErr::IntegerOverflow(_, span) => Some(span)
+    }
+  }
+}
+
+impl fmt::Display for LexErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      LexErr::MismatchedQuotes(_) => write!(f, "mismatched quote"),
+      LexErr::UnknownToken(ref token, _) => write!(f, "unknown token \"{}\"", token),
+      LexErr::UnknownEscapeSequence(c, _) => write!(f, "unknown escape sequence: \"{}\"", c),
+      LexErr::IntegerOverflow(ref literal, _) => write!(f, "integer literal {} does not fit in 64 bits", literal)
+    }
+  }
 }
 
+impl error::Error for LexErr {}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Token {
   // operators
   Equals,
+  PlusEquals,
+  MinusEquals,
+  AsterixEquals,
+  SlashEquals,
   Plus,
   Minus,
   Asterix,
   DoubleAsterix,
   Slash,
+  TildeSlash,
+  Percent,
   Bang,
+  Ampersand,
+  Pipe,
+  Caret,
+  DoubleLt,
+  DoubleGt,
 
   SemiColon,
   Colon,
   Dot,
+  DotDot,
   Comma,
   BraceClose,
   BraceOpen,
@@ -40,6 +80,8 @@ pub enum Token {
 
   ParOpen,
   ParClose,
+  BracketOpen,
+  BracketClose,
   
   EqualsEquals,
   BangEquals,
@@ -48,19 +90,27 @@ pub enum Token {
   Gt,
   Lt,
 
+  And,
+  Or,
+
   LineBreak,
   Tab,
 
   // keywords
   Let,
   New,
-  Ref,
   DeRef,
   Struct,
+  Extends,
   If,
   Else,
   While,
+  For,
+  In,
   Func,
+  Return,
+  Break,
+  Continue,
   True,
   False,
   Nil,
@@ -76,16 +126,28 @@ use self::Token::*;
 pub fn get_tokens<'a>() -> HashMap<&'a str, Token> {
   map!{
     "=" => Equals,
+    "+=" => PlusEquals,
+    "-=" => MinusEquals,
+    "*=" => AsterixEquals,
+    "/=" => SlashEquals,
     "+" => Plus,
     "-" => Minus,
     "*" => Asterix,
     "**" => DoubleAsterix,
     "/" => Slash,
+    "~/" => TildeSlash,
+    "%" => Percent,
     "!" => Bang,
+    "&" => Ampersand,
+    "|" => Pipe,
+    "^" => Caret,
+    "<<" => DoubleLt,
+    ">>" => DoubleGt,
 
     ";" => SemiColon,
     ":" => Colon,
     "." => Dot,
+    ".." => DotDot,
     "," => Comma,
     "{" => BraceOpen,
     "}" => BraceClose,
@@ -93,6 +155,8 @@ pub fn get_tokens<'a>() -> HashMap<&'a str, Token> {
 
     "(" => ParOpen,
     ")" => ParClose,
+    "[" => BracketOpen,
+    "]" => BracketClose,
     
     "==" => EqualsEquals,
     "!=" => BangEquals,
@@ -101,18 +165,26 @@ pub fn get_tokens<'a>() -> HashMap<&'a str, Token> {
     ">" => Gt,
     "<" => Lt,
 
+    "&&" => And,
+    "||" => Or,
+
     "\n" => LineBreak,
     "\t" => Tab,
 
     "let" => Let,
     "new" => New,
-    "&" => Ref,
     // "*" => DeRef,
     "struct" => Struct,
+    "extends" => Extends,
     "if" => If,
     "while" => While,
+    "for" => For,
+    "in" => In,
     "else" => Else,
     "fn" => Func,
+    "return" => Return,
+    "break" => Break,
+    "continue" => Continue,
     "true" => True,
     "false" => False,
     "nil" => Nil,
@@ -7,12 +7,14 @@ pub use self::info::LexErr;
 pub use self::info::get_tokens;
 
 use parser::Declaration;
+use span::{Span, LineMap};
 
 use std::collections::HashMap;
 
 #[derive(Debug)]
 enum PreLexed {
-  String(String, i32),
+  // escapes resolved, pos, length in the source
+  String(String, i32, i32),
   Rest(String, i32),
   Comment(String)
 }
@@ -21,6 +23,7 @@ enum PreLexed {
 pub enum Literal {
   String(String),
   Num(f64),
+  Int(i64),
   Bool(bool),
   Nil,
 
@@ -33,9 +36,9 @@ pub enum Literal {
 
 #[derive(Debug)]
 pub enum Lexed {
-  Literal(Literal, i32),
-  Operator(Token, i32),
-  Identifier(String, i32),
+  Literal(Literal, Span),
+  Operator(Token, Span),
+  Identifier(String, Span),
 }
 
 fn remove_comments(query: &str) -> Result<Vec<PreLexed>, LexErr> {
@@ -81,17 +84,19 @@ fn remove_comments(query: &str) -> Result<Vec<PreLexed>, LexErr> {
   Ok(pre_lexed)
 }
 
-fn resolve_escapes(query: &str, pos: i32) -> Result<String, LexErr> {
+fn resolve_escapes(query: &str, pos: i32, lines: &LineMap) -> Result<String, LexErr> {
   let mut s = String::from("");
 
-  fn get_seq<'a>(c: char, pos: i32) -> Result<char, LexErr> {
+  // pos is the char after the backslash
+  let get_seq = |c: char, pos: i32| -> Result<char, LexErr> {
     Ok(match c {
       'n' => '\n',
       't' => '\t',
+      '0' => '\0',
       '"' => '\"',
-      _ => return Err(LexErr::UnknownEscapeSequence(c, pos))
+      _ => return Err(LexErr::UnknownEscapeSequence(c, lines.span(pos - 1, pos + 1)))
     })
-  }
+  };
 
   let mut in_escape = false;
 
@@ -118,7 +123,7 @@ fn resolve_escapes(query: &str, pos: i32) -> Result<String, LexErr> {
   Ok(s)
 }
 
-fn pre_lex(query: &str) -> Result<Vec<PreLexed>, LexErr> {
+fn pre_lex(query: &str, lines: &LineMap) -> Result<Vec<PreLexed>, LexErr> {
   let query = query.to_string();
   let removed_comments = remove_comments(&query)?;
 
@@ -158,7 +163,7 @@ fn pre_lex(query: &str) -> Result<Vec<PreLexed>, LexErr> {
               pre_lexed.push(if in_quote {
                 PreLexed::Rest(s.to_string(), apos)
               } else {
-                PreLexed::String(resolve_escapes(s, apos)?, apos)
+                PreLexed::String(resolve_escapes(s, apos, lines)?, apos, s.chars().count() as i32)
               });
 
               start = i+1;
@@ -170,7 +175,8 @@ fn pre_lex(query: &str) -> Result<Vec<PreLexed>, LexErr> {
         }
 
         if quote_count % 2 != 0 {
-          return Err(LexErr::MismatchedQuotes((start - 1) as i32 + comment_offset as i32 + rest_offset as i32));
+          let pos = (start - 1) as i32 + comment_offset as i32 + rest_offset as i32;
+          return Err(LexErr::MismatchedQuotes(lines.span(pos, pos + 1)));
         }
 
         pre_lexed.push(PreLexed::Rest(query[start..query.len()].to_string(), start as i32 + comment_offset as i32 + rest_offset as i32));
@@ -293,7 +299,7 @@ fn trim<'a>(val: &'a str) -> (&'a str, i32, i32) {
   (&val[start as usize..end as usize], start, end)
 }
 
-fn tokenize(pre_lexed: Vec<PreLexed>) -> Result<Vec<Lexed>, LexErr> {
+fn tokenize(pre_lexed: Vec<PreLexed>, lines: &LineMap) -> Result<Vec<Lexed>, LexErr> {
   let prev_tokens: HashMap<&str, Token> = info::get_tokens();
   let tokens: Vec<(&&str, &Token)> = prev_tokens.iter().collect();
   // tokens.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
@@ -330,6 +336,7 @@ fn tokenize(pre_lexed: Vec<PreLexed>) -> Result<Vec<Lexed>, LexErr> {
           let curr = &val[offset..len - r_offset as usize];
           let trimmed = trim(curr);
           let apos = pos + trimmed.1 + offset as i32 + pre_offset;
+          let span = lines.span(apos, apos + trimmed.0.chars().count() as i32);
           // println!("curr: {}, trim: {}, off: {}, r_off: {}, lex: {}", curr, trimmed.0, offset, r_offset, lexed.len());
 
           if curr.len() <= 0 {
@@ -340,17 +347,26 @@ fn tokenize(pre_lexed: Vec<PreLexed>) -> Result<Vec<Lexed>, LexErr> {
 
           if let Some(op) = is_operator(&tokens, trimmed.0) {
             match op {
-              Token::True => lexed.push(Lexed::Literal(Literal::Bool(true), apos)),
-              Token::False => lexed.push(Lexed::Literal(Literal::Bool(false), apos)),
-              Token::Nil => lexed.push(Lexed::Literal(Literal::Nil, apos)),
-              _ => lexed.push(Lexed::Operator(op, apos))
+              Token::True => lexed.push(Lexed::Literal(Literal::Bool(true), span)),
+              Token::False => lexed.push(Lexed::Literal(Literal::Bool(false), span)),
+              Token::Nil => lexed.push(Lexed::Literal(Literal::Nil, span)),
+              _ => lexed.push(Lexed::Operator(op, span))
             }
           } else if is_identifier(trimmed.0) {
-            lexed.push(Lexed::Identifier(String::from(trimmed.0), apos));
+            lexed.push(Lexed::Identifier(String::from(trimmed.0), span));
           } else if is_number(trimmed.0) {
-            lexed.push(Lexed::Literal(Literal::Num(trimmed.0.parse::<f64>().unwrap()), apos));
+            // numbers without a fraction or exponent are ints
+            let literal = if trimmed.0.chars().all(|c| c.is_ascii_digit()) {
+              match trimmed.0.parse::<i64>() {
+                Ok(int) => Literal::Int(int),
+                Err(_) => return Err(LexErr::IntegerOverflow(trimmed.0.to_string(), span))
+              }
+            } else {
+              Literal::Num(trimmed.0.parse::<f64>().unwrap())
+            };
+            lexed.push(Lexed::Literal(literal, span));
           } else if trimmed.0.len() <= 1 {
-            return Err(LexErr::UnknownToken(trimmed.0.to_string(), apos));
+            return Err(LexErr::UnknownToken(trimmed.0.to_string(), span));
           } else {
             continue;
           }
@@ -359,11 +375,12 @@ fn tokenize(pre_lexed: Vec<PreLexed>) -> Result<Vec<Lexed>, LexErr> {
           offset += trimmed.0.len() + trimmed.1 as usize;
         }
       },
-      PreLexed::String(val, pos) => {
+      PreLexed::String(val, pos, len) => {
         last_pos = pos;
-        last_len = val.len();
+        last_len = len as usize + 1;
 
-        lexed.push(Lexed::Literal(Literal::String(String::from(val)), pos));
+        // including the quotes
+        lexed.push(Lexed::Literal(Literal::String(String::from(val)), lines.span(pos - 1, pos + len + 1)));
       }
     };
   }
@@ -377,14 +394,16 @@ fn tokenize(pre_lexed: Vec<PreLexed>) -> Result<Vec<Lexed>, LexErr> {
     _ => true
   });
 
-  lexed.push(Lexed::Operator(Token::EOF, last_pos + last_len as i32));
+  let end = last_pos + last_len as i32;
+  lexed.push(Lexed::Operator(Token::EOF, lines.span(end, end)));
 
   Ok(lexed)
 }
 
 /// Lexes a `query: &str` into a vector of tokens: `Vec<Lexed>`.
 pub fn lex(query: &str) -> Result<Vec<Lexed>, LexErr> {
-  let lexed = pre_lex(query)?;
-  let tokenized = tokenize(lexed)?;
+  let lines = LineMap::new(query);
+  let lexed = pre_lex(query, &lines)?;
+  let tokenized = tokenize(lexed, &lines)?;
   Ok(tokenized)
 }
\ No newline at end of file
@@ -4,11 +4,14 @@
 pub mod lexer;
 pub mod parser;
 pub mod vm;
+pub mod span;
 
 use std::fs::File;
 use std::io::prelude::*;
+use std::{error, fmt};
 
 use vm::{VM, BuildOptions, Program, Instructions};
+use span::Source;
 use lexer::Literal;
 
 mod handle_err;
@@ -16,13 +19,42 @@ mod handle_err;
 #[derive(Debug)]
 pub enum LangErr {
   LexErr(lexer::LexErr),
-  ParserErr(parser::ParserErr),
+  ParserErr(Vec<parser::ParserErr>), // every statement that failed, at least one
   // InterpreterErr(interpreter::InterpreterErr),
   VMExecErr(vm::VMExecError),
   VMBuildErr(vm::VMBuildError)
 }
 
-fn do_build(query: &str, vm: &mut VM, options: BuildOptions) -> Result<Instructions, LangErr> {
+impl fmt::Display for LangErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      LangErr::LexErr(ref err) => err.fmt(f),
+      LangErr::ParserErr(ref errs) => {
+        for (i, err) in errs.iter().enumerate() {
+          if i > 0 {
+            writeln!(f)?;
+          }
+          err.fmt(f)?;
+        }
+        Ok(())
+      },
+      LangErr::VMExecErr(ref err) => err.fmt(f),
+      LangErr::VMBuildErr(ref err) => err.fmt(f)
+    }
+  }
+}
+
+impl error::Error for LangErr {}
+
+/// How `build_file` and `exec` report errors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+  Text, // coloured, with the code the error points to
+  Json // one object per line, for editors and CI
+}
+
+fn do_build(source: &Source, vm: &mut VM, options: BuildOptions) -> Result<Instructions, LangErr> {
+  let query: &str = &source.code;
   let lexed = match lexer::lex(query) {
     Ok(val) => val,
     Err(err) => return Err(LangErr::LexErr(err))
@@ -33,12 +65,9 @@ fn do_build(query: &str, vm: &mut VM, options: BuildOptions) -> Result<Instructi
     Err(err) => return Err(LangErr::ParserErr(err))
   };
 
-  let mut debug_offset = vm.vm_exec.query.len();
-  if debug_offset > 0 {
-    vm.vm_exec.query += "\n\n\n";
-    debug_offset += 3;
-  }
-  let instructions = match vm.build(parsed, String::from(query), debug_offset, options) {
+  // in the shell, the spans follow the ones of the code already run
+  let span_offset = vm.vm_exec.spans.len();
+  let instructions = match vm.build(parsed, source.clone(), span_offset, options) {
     Ok(val) => val,
     Err(err) => return Err(LangErr::VMBuildErr(err))
   };
@@ -47,10 +76,19 @@ fn do_build(query: &str, vm: &mut VM, options: BuildOptions) -> Result<Instructi
 }
 
 pub fn build(query: &str, vm: &mut VM, options: BuildOptions) -> Result<Instructions, LangErr> {
-  match do_build(query, vm, options) {
+  build_file("", query, vm, options)
+}
+
+/// Builds the code of the file `name`, which errors and the debug info refer to.
+pub fn build_file(name: &str, query: &str, vm: &mut VM, options: BuildOptions) -> Result<Instructions, LangErr> {
+  let source = Source::new(name, query);
+  match do_build(&source, vm, options) {
     Ok(val) => Ok(val),
     Err(err) => {
-      handle_err::handle_err(&err, query);
+      match vm.error_format {
+        ErrorFormat::Text => handle_err::handle_err(&err, &[source], &[]),
+        ErrorFormat::Json => println!("{}", handle_err::err_json(&err, &[source], &[], &[]))
+      }
       Err(err)
     }
   }
@@ -60,11 +98,23 @@ pub fn exec(program: Program, vm: &mut VM, append: bool) -> Result<String, LangE
   match vm.exec(program, append) {
     Ok(val) => Ok(val),
     Err(err) => {
+      let sources = &vm.vm_exec.sources;
+      let spans = &vm.vm_exec.spans;
+      let trace = handle_err::trace_notes(&err, vm.vm_exec.trace(), sources, spans);
       let err = LangErr::VMExecErr(err);
-      if vm.vm_exec.is_debug {
-        handle_err::handle_err(&err, &vm.vm_exec.query);
-      } else {
-        handle_err::print_err_text(&format!("{:?}\n", err));
+
+      match vm.error_format {
+        ErrorFormat::Text => {
+          if vm.vm_exec.is_debug {
+            handle_err::handle_err(&err, sources, spans);
+          } else {
+            handle_err::print_err_text(&format!("{:?}\n", err));
+          }
+          for note in &trace {
+            println!("  {}", note);
+          }
+        },
+        ErrorFormat::Json => println!("{}", handle_err::err_json(&err, sources, spans, &trace))
       }
       Err(err)
     }
@@ -73,8 +123,763 @@ pub fn exec(program: Program, vm: &mut VM, append: bool) -> Result<String, LangE
 
 #[cfg(test)]
 mod tests {
+    use vm::{self, VM, BuildOptions, VMBuildError, VMExecError};
+    use vm::exec::{VMExec, Value, Literal};
+    use vm::verify::VerifyError;
+    use vm::format::{self, FormatError};
+    use LangErr;
+    use handle_err;
+    use span::Source;
+    use lexer;
+
+    fn run(query: &str, options: BuildOptions) -> String {
+        let mut vm = VM::new();
+        let instructions = super::build(query, &mut vm, options).unwrap();
+        super::exec(vm::get_program(instructions).unwrap(), &mut vm, false).unwrap()
+    }
+
+    fn run_all(query: &str) -> String {
+        let res = run(query, BuildOptions::DEBUG | BuildOptions::CODE);
+        assert_eq!(res, run(query, BuildOptions::NONE));
+        res
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn break_nested_loops() {
+        assert_eq!(run_all("
+            let log = \"\";
+            let i = 0;
+            while (i = i + 1) < 4 {
+                let j = 0;
+                while (j = j + 1) < 10 {
+                    if j == 2 { break };
+                    log = log + i + j;
+                };
+            };
+            log
+        "), "\"112131\"");
+    }
+
+    #[test]
+    fn break_with_value() {
+        assert_eq!(run_all("let r = while true { let x = 5; break x * 2 }; r"), "10");
+        assert_eq!(run_all("1 + while true { if true { break 2 } }"), "3");
+        assert_eq!(run_all("while false { 1 }"), "nil");
+    }
+
+    #[test]
+    fn continue_loop() {
+        assert_eq!(run_all("
+            let i = 0;
+            let log = \"\";
+            while (i = i + 1) < 6 {
+                if i == 2 || i == 4 { continue };
+                log = log + i;
+            };
+            log
+        "), "\"135\"");
+
+        let mut vm = VM::new();
+        for query in &["break", "let x = 1; break x", "if true { break }", "fn() { break }"] {
+            match super::build(query, &mut vm, BuildOptions::NONE) {
+                Err(LangErr::VMBuildErr(VMBuildError::BreakOutsideLoop(..))) => {},
+                res => panic!("expected break outside of loop for {}, got {:?}", query, res)
+            }
+        }
+        for query in &["continue", "let x = 1; continue", "while true { fn() { continue } }"] {
+            match super::build(query, &mut vm, BuildOptions::NONE) {
+                Err(LangErr::VMBuildErr(VMBuildError::ContinueOutsideLoop(..))) => {},
+                res => panic!("expected continue outside of loop for {}, got {:?}", query, res)
+            }
+        }
+    }
+
+    #[test]
+    fn equality() {
+        assert_eq!(run_all("let a = !true; let b = !false; let c = !(1 == 2); let d = !!true; format(a, b, c, d)"), "\"falsetruetruetrue\"");
+        assert_eq!(run_all("format(1 == 1, 1 != 1, 1 == 2, 1 != 2)"), "\"truefalsefalsetrue\"");
+        assert_eq!(run_all("format(1 == 1.0, 1.5 == 1, 2.0 != 2)"), "\"truefalsefalse\"");
+        assert_eq!(run_all("format(2 >= 1, 1 >= 2, 1 >= 1, 1 <= 2)"), "\"truefalsetruetrue\"");
+        assert_eq!(run_all("format(nil == nil, nil == false, false == 0, nil != false)"), "\"truefalsefalsetrue\"");
+        assert_eq!(run_all("format(\"1\" == 1, 1 == \"1\", \"a\" == \"a\", \"a\" != \"b\")"), "\"falsefalsetruetrue\"");
+        assert_eq!(run_all("
+            let S = struct { let v = 0; };
+            let a = new S();
+            let b = new S();
+            let c = a;
+            let f = fn() { 1 };
+            let g = fn() { 1 };
+            let h = f;
+            format(a == c, a == b, a != b, f == h, f == g, print == print, print == format, a == f)
+        "), "\"truefalsetruetruefalsetruefalsefalse\"");
+    }
+
+    #[test]
+    fn short_circuit() {
+        assert_eq!(run_all("
+            let calls = 0;
+            let hit = fn(res) { calls = calls + 1; res };
+            let a = false && hit(true);
+            let b = true || hit(false);
+            let c = true && hit(true);
+            let d = false || hit(false);
+            format(a, b, c, d, calls)
+        "), "\"falsetruetruefalse2\"");
+
+        // the right side would fail if it ran
+        assert_eq!(run_all("false && nope()"), "false");
+        assert_eq!(run_all("true || 1 + \"a\" * nil"), "true");
+        let mut vm = VM::new();
+        let instructions = super::build("true && nope()", &mut vm, BuildOptions::NONE).unwrap();
+        match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+            Err(LangErr::VMExecErr(_)) => {},
+            res => panic!("expected the right side to fail, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn returns() {
+        assert_eq!(run_all("
+            let find = fn(target) {
+                let i = 0;
+                while (i = i + 1) < 10 {
+                    let j = 0;
+                    while (j = j + 1) < 10 {
+                        if i * j == target { return format(i, j) };
+                    };
+                };
+                \"none\"
+            };
+            format(find(12), find(97))
+        "), "\"26none\"");
+        assert_eq!(run_all("let f = fn() { return; 1 }; f()"), "nil");
+        assert_eq!(run_all("let f = fn() { if true { return } }; f()"), "nil");
+
+        let mut vm = VM::new();
+        for query in &["return", "return 1", "let x = 1; if x == 1 { return x }", "while true { return }"] {
+            match super::build(query, &mut vm, BuildOptions::NONE) {
+                Err(LangErr::VMBuildErr(VMBuildError::ReturnOutsideFunction(..))) => {},
+                res => panic!("expected return outside of function for {}, got {:?}", query, res)
+            }
+        }
+    }
+
+    #[test]
+    fn closures() {
+        assert_eq!(run_all("
+            let counter = fn() {
+                let count = 0;
+                fn() { count = count + 1; count }
+            };
+            let a = counter();
+            let b = counter();
+            a(); a();
+            format(a(), b())
+        "), "\"31\"");
+        assert_eq!(run_all("
+            let x = \"global\";
+            let show = fn() { x };
+            let shadow = fn() { let x = \"local\"; show() };
+            shadow()
+        "), "\"global\"");
+    }
+
+    #[test]
+    fn garbage_collection() {
+        for &options in &[BuildOptions::DEBUG | BuildOptions::CODE, BuildOptions::NONE] {
+            let mut vm = VM::new();
+            let instructions = super::build("
+                let i = 0;
+                let n = 0;
+                while (i = i + 1) < 5000 {
+                    let garbage = \"g\" + i;
+                    if (n = n + 1) == 100 { gc(); n = 0 };
+                };
+                i
+            ", &mut vm, options).unwrap();
+            assert_eq!(super::exec(vm::get_program(instructions).unwrap(), &mut vm, false).unwrap(), "5000");
+
+            let stats = vm.vm_exec.gc_stats();
+            assert!(stats.collections >= 49, "{} collections", stats.collections);
+            assert!(stats.freed_values > 5000, "{} values freed", stats.freed_values);
+            assert!(stats.live_values < 2048, "{} values live", stats.live_values);
+        }
+    }
+
+    #[test]
+    fn gc_keeps_reachable() {
+        assert_eq!(run_all("
+            let P = struct { let n = 0; let __init__ = fn(v) { n = v; }; let get = fn() { n }; };
+            let counter = fn() {
+                let count = 0;
+                fn() { count = count + 1; count }
+            };
+            let c = counter();
+            let arr = [1, [2, 3], {\"k\": \"v\"}];
+            let p = new P(7);
+            c();
+            gc();
+            let i = 0;
+            while (i = i + 1) < 3000 { let garbage = [i, \"g\" + i]; };
+            gc();
+            format(c(), arr[1][1], arr[2][\"k\"], p.get(), p.n)
+        "), "\"23v77\"");
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(run_all("
+            let xs = [1, 2, [3]];
+            let ys = xs;
+            ys[0] = 10;
+            push(xs[2], 4);
+            push(ys, pop(xs), \"end\");
+            format(xs, len(xs), slice(xs, 1, 2))
+        "), "\"[10, 2, [3, 4], \"end\"]4[2]\"");
+        assert_eq!(run_all("let fs = [fn(x) { x * 2 }]; fs[0](21)"), "42");
+        assert_eq!(run_all("\"abc\"[1]"), "\"b\"");
+    }
+
+    #[test]
+    fn maps() {
+        assert_eq!(run_all("
+            let m = { \"b\": 1, \"a\": [2] };
+            m[\"c\"] = m[\"b\"] + 2;
+            remove(m, \"b\");
+            format(m, keys(m), values(m)[1], has(m, \"b\"), len(m))
+        "), "\"{\"a\": [2], \"c\": 3}[\"a\", \"c\"]3false2\"");
+    }
+
+    #[test]
+    fn for_loops() {
+        assert_eq!(run_all("
+            let log = \"\";
+            for i in 0..3 { log = log + i };
+            for c in \"ab\" { log = log + c };
+            for x in [4, 5] { log = log + x };
+            for k in { \"y\": 1, \"x\": 2 } { log = log + k };
+            log
+        "), "\"012ab45xy\"");
+        assert_eq!(run_all("for i in 0..10 { if i == 1 { continue }; if i == 3 { break i * 10 }; i }"), "30");
+        assert_eq!(run_all("
+            let fs = [];
+            for i in 0..3 { push(fs, fn() { i }) };
+            format(fs[0](), fs[2]())
+        "), "\"02\"");
+        assert_eq!(run_all("
+            let Squares = struct {
+                let n = 0;
+                let i = 0;
+                let __init__ = fn(count) { n = count; };
+                let __iter__ = fn() { i = 0; self };
+                let __next__ = fn() {
+                    if i >= n { return nil };
+                    i = i + 1;
+                    i * i
+                };
+            };
+            let log = \"\";
+            let squares = new Squares(3);
+            for x in squares { log = log + x };
+            for x in squares { log = log + x };
+            log
+        "), "\"149149\"");
+        // __next__ runs as a bound method, nesting two iterators keeps each self apart
+        assert_eq!(run_all("
+            let Count = struct {
+                let n = 0;
+                let i = 0;
+                let __init__ = fn(n) { self.n = n; };
+                let __iter__ = fn() { self.i = 0; self };
+                let __next__ = fn() {
+                    if self.i >= self.n { return nil };
+                    self.i += 1;
+                    self.i
+                };
+            };
+            let log = \"\";
+            let a = new Count(2);
+            let b = new Count(3);
+            for x in a { for y in b { log = log + x + y }; log = log + \"|\" };
+            format(log, a.i, b.i)
+        "), "\"111213|212223|23\"");
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(run_all("format(7 ~/ 2, -7 ~/ 2, -7 % 3, 7 % -3, 7 / 2, 1 + 0.5, 7.5 % 2)"), "\"3-42-23.51.51.5\"");
+        assert_eq!(run_all("len([1, 2]) * 2 == 4"), "true");
+        assert_eq!(run_all("(-9223372036854775807 - 1) % -1"), "0");
+
+        let mut vm = VM::new();
+        for query in &["9223372036854775807 + 1", "add(9223372036854775807, 1)"] {
+            let instructions = super::build(query, &mut vm, BuildOptions::DEBUG).unwrap();
+            match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+                Err(LangErr::VMExecErr(VMExecError::IntegerOverflow(_, pos))) => assert!(pos >= 0, "no position for {}", query),
+                res => panic!("expected overflow for {}, got {:?}", query, res)
+            }
+        }
+
+        assert_eq!(run_all("format(\"ab\" * 3, 2 * \"c\", \"d\" * -1, \"e\" * 2.0)"), "\"abababccee\"");
+        for query in &["\"a\" * 9223372036854775807", "\"a\" * 10000000000000.0", "\"a\" * (1 / 0.0)"] {
+            let instructions = super::build(query, &mut vm, BuildOptions::DEBUG).unwrap();
+            match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+                Err(LangErr::VMExecErr(VMExecError::StringTooLong(1, _, pos))) => assert!(pos >= 0, "no position for {}", query),
+                res => panic!("expected a too long string for {}, got {:?}", query, res)
+            }
+        }
+    }
+
+    #[test]
+    fn pow_and_bitwise() {
+        assert_eq!(run_all("format(2 ** 10, 2 ** 3 ** 2, -2 ** 2, 2 ** -1, 4.0 ** 0.5)"), "\"1024512-40.52\"");
+        assert_eq!(run_all("format(6 & 3, 6 | 3, 6 ^ 3, 1 << 4, -16 >> 2, 1 >> 70)"), "\"27516-40\"");
+        // shifts bind looser than + and tighter than &, which binds tighter than comparisons
+        assert_eq!(run_all("format(1 + 1 << 2, 6 & 3 == 2, 1 | 2 ^ 3 & 5, 0..1 << 2)"), "\"8true30..4\"");
+
+        let mut vm = VM::new();
+        for query in &["2 ** 63", "1 << 63", "1 << -1", "1.5 & 1"] {
+            let instructions = super::build(query, &mut vm, BuildOptions::NONE).unwrap();
+            match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+                Err(LangErr::VMExecErr(VMExecError::IntegerOverflow(..))) |
+                Err(LangErr::VMExecErr(VMExecError::UnsupportedOperation(..))) => {},
+                res => panic!("expected an error for {}, got {:?}", query, res)
+            }
+        }
+    }
+
+    #[test]
+    fn compound_assignment() {
+        assert_eq!(run_all("
+            let i = 1;
+            i += 2; i *= 5; i -= 1;
+            let r = i /= 4;
+            format(i, r)
+        "), "\"3.53.5\"");
+        // the target is only evaluated once
+        assert_eq!(run_all("
+            let S = struct { let count = 0; };
+            let s = new S();
+            let calls = 0;
+            let get = fn() { calls += 1; s };
+            (get()).count += 10;
+            let xs = [1, [2]];
+            let at = fn(i) { calls += 1; i };
+            xs[at(1)][at(0)] *= 3;
+            let m = { \"a\": \"x\" };
+            m[\"a\"] += \"y\";
+            format(s.count, calls, xs, m)
+        "), "\"103[1, [6]]{\"a\": \"xy\"}\"");
+    }
+
+    #[test]
+    fn field_assignment() {
+        assert_eq!(run_all("
+            let Inner = struct { let c = 0; let inc = fn() { self.c += 1; }; };
+            let Outer = struct { let b = new Inner(); };
+            let a = new Outer();
+            a.b.c = 5;
+            a.b.inc();
+            let a.d = 1;
+            a.d = 2;
+            format(a.b.c, a.d)
+        "), "\"62\"");
+
+        // fields are never looked up outside of the instance
+        let mut vm = VM::new();
+        let setup = "let x = 1; let S = struct { let c = 0; }; let s = new S();";
+        for query in &["s.x = 2", "s.y = 2", "s.x", "s.x + 1"] {
+            let instructions = super::build(&format!("{} {}", setup, query), &mut vm, BuildOptions::NONE).unwrap();
+            match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+                Err(LangErr::VMExecErr(VMExecError::FieldNotDefined(..))) => {},
+                res => panic!("expected field not defined for {}, got {:?}", query, res)
+            }
+        }
+    }
+
+    #[test]
+    fn bound_methods() {
+        assert_eq!(run_all("
+            let S = struct {
+                let v = 0;
+                let __init__ = fn(x) { self.v = x; };
+                let get = fn() { self.v };
+            };
+            let a = new S(1);
+            let b = new S(2);
+            let f = a.get;
+            let call = fn(cb) { cb() };
+            let show = fn() { self.v * 10 };
+            let a.show = show;
+            let b.show = show;
+            let a.other = b.get;
+            format(f(), call(b.get), a.show(), b.show(), a.other(), a.get == a.get, a.get == b.get)
+        "), "\"1210202truefalse\"");
+    }
+
+    #[test]
+    fn inheritance() {
+        assert_eq!(run_all("
+            let Animal = struct {
+                let name = \"\";
+                let legs = 4;
+                let __init__ = fn(name) { self.name = name; };
+                let sound = fn() { \"...\" };
+                let describe = fn() { self.name + \" says \" + self.sound() };
+            };
+            let Dog = struct extends Animal {
+                let __init__ = fn(name) { super.__init__(name + \"!\"); };
+                let sound = fn() { \"woof\" };
+                let plain = fn() { super.sound() };
+            };
+            let Puppy = struct extends Dog {
+                let sound = fn() { \"yip\" };
+            };
+            let d = new Dog(\"rex\");
+            let other = new Dog(\"max\");
+            let p = new Puppy();
+            d.legs = 3;
+            format(d.describe(), \"|\", d.plain(), d.legs, other.legs, \"|\", p.describe())
+        "), "\"rex! says woof|...34| says yip\"");
+    }
+
+    #[test]
+    fn disassemble() {
+        let query = "let f = fn(a) { a };\nfor i in 0..2 { if i == 1 { break }; f(1.5) }";
+        let mut vm = VM::new();
+        let instructions = super::build(query, &mut vm, BuildOptions::DEBUG | BuildOptions::CODE).unwrap();
+        let listing = vm::disasm::disassemble(&vm::get_program(instructions).unwrap());
+
+        assert!(listing.contains("PUSH_VAR \"f\" @1:5"));
+        assert!(listing.contains("PUSH_CONST 1.5"));
+        assert!(listing.contains("RANGE @2:11"));
+        assert!(listing.contains("; 2: for i in 0..2"));
+
+        // every jump lands on the start of an instruction
+        let starts: Vec<&str> = listing.lines()
+            .filter(|line| line.starts_with(|c: char| c.is_digit(10)))
+            .map(|line| &line[..4])
+            .collect();
+        for target in listing.split("-> ").skip(1) {
+            assert!(starts.contains(&&target[..4]), "{}", listing);
+        }
+    }
+
+    #[test]
+    fn file_format() {
+        let mut vm = VM::new();
+        let mut instructions = super::build("258", &mut vm, BuildOptions::NONE).unwrap();
+        let module = format::read(&instructions).unwrap();
+
+        // numbers are little-endian
+        assert_eq!(module.constants, vec![format::Constant::Int(258)]);
+        assert!(instructions.windows(8).any(|bytes| bytes == [2, 1, 0, 0, 0, 0, 0, 0]));
+        assert!(!module.is_debug && module.source.is_none());
+
+        let source = "let a = 1;\n\nprint(a)";
+        let debug = format::read(&super::build(source, &mut VM::new(), BuildOptions::DEBUG | BuildOptions::CODE).unwrap()).unwrap();
+        assert_eq!(debug.source, Some(source.to_string()));
+        assert_eq!(debug.line_at(0), Some(1));
+        assert_eq!(debug.line_at(debug.code.len() - 1), Some(3));
+
+        let mut corrupt = instructions.clone();
+        corrupt[10] ^= 0x01;
+        match vm::get_program(corrupt) {
+            Err(VMExecError::FormatErr(FormatError::ChecksumMismatch(..))) => {},
+            res => panic!("expected checksum mismatch, got {:?}", res)
+        }
+
+        instructions[format::MAGIC.len()] = vm::BYTECODE_VERSION - 1;
+        match vm::get_program(instructions) {
+            Err(VMExecError::FormatErr(FormatError::VersionMismatch(found, expected))) => assert_eq!((found, expected), (vm::BYTECODE_VERSION - 1, vm::BYTECODE_VERSION)),
+            res => panic!("expected version mismatch, got {:?}", res)
+        }
+        match vm::get_program(vec![]) {
+            Err(VMExecError::FormatErr(FormatError::InvalidMagic)) => {},
+            res => panic!("expected invalid magic, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn constant_pool() {
+        let query = "let s = \"a\\0b\"; print(\"a\\0b\", 1.5); print(1.5, s); len(\"a\\0b\")";
+        let mut vm = VM::new();
+        let module = format::read(&super::build(query, &mut vm, BuildOptions::NONE).unwrap()).unwrap();
+
+        assert_eq!(module.constants, vec![format::Constant::String("a\0b".to_string()), format::Constant::Num(1.5)]);
+        assert_eq!(module.symbols, vec!["s", "print", "len"]);
+        assert_eq!(run_all(query), "3");
+    }
+
+    #[test]
+    fn verifier() {
+        let mut vm = VM::new();
+        let module = format::read(&super::build("let i = 0; while i < 3 { i = i + 1 }", &mut vm, BuildOptions::DEBUG).unwrap()).unwrap();
+        let instructions = module.code.clone();
+
+        let verify = |code: Vec<u8>| {
+            let mut module = module.clone();
+            module.code = code;
+            vm::verify::verify(&vm::get_program(format::write(&module)).unwrap())
+        };
+        assert!(verify(instructions.clone()).is_ok());
+
+        let mut truncated = instructions.clone();
+        truncated.truncate(instructions.len() - 3);
+        assert!(verify(truncated).is_err());
+
+        let mut unknown = instructions.clone();
+        unknown.insert(instructions.len() - 1, 0xff);
+        match verify(unknown) {
+            Err(VerifyError::UnknownOPCode(0xff, _)) => {},
+            res => panic!("expected unknown opcode, got {:?}", res)
+        }
+
+        // point the loop's jump back one byte before its target
+        let jump = instructions.iter().rposition(|&op| op == vm::u(vm::OPCode::JUMP)).unwrap();
+        let mut bad_jump = instructions.clone();
+        bad_jump[jump + 2] = bad_jump[jump + 2].wrapping_sub(1);
+        match verify(bad_jump) {
+            Err(VerifyError::InvalidJump(pos, _)) => assert_eq!(pos, jump),
+            res => panic!("expected invalid jump, got {:?}", res)
+        }
+
+        let mut no_end = instructions.clone();
+        no_end.pop();
+        match verify(no_end) {
+            Err(VerifyError::MissingEnd) => {},
+            res => panic!("expected missing end, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn corrupt_counts() {
+        let mut vm = VM::new();
+        let module = format::read(&super::build("let f = fn(a) { a }; f([1, 2])", &mut vm, BuildOptions::NONE).unwrap()).unwrap();
+
+        // replaces the count operand of the last `code` instruction, the checksum stays valid
+        let with_count = |code: vm::OPCode, count: i32| {
+            let mut module = module.clone();
+            let at = module.code.iter().rposition(|&op| op == vm::u(code)).unwrap();
+            module.code[at + 2..at + 6].copy_from_slice(&count.to_le_bytes());
+            let mut vm = VM::new();
+            vm.exec(vm::get_program(format::write(&module)).unwrap(), false)
+        };
+
+        for &code in &[vm::OPCode::CALL_FUNC, vm::OPCode::PUSH_ARRAY] {
+            match with_count(code, i32::MAX) {
+                Err(VMExecError::StackUnderflow(count, _, _)) => assert_eq!(count, i32::MAX as i64),
+                res => panic!("expected stack underflow for {:?}, got {:?}", code, res)
+            }
+            match with_count(code, -1) {
+                Err(VMExecError::VerifyErr(VerifyError::InvalidOperand(..))) => {},
+                res => panic!("expected invalid operand for {:?}, got {:?}", code, res)
+            }
+        }
+    }
+
+    #[test]
+    fn stray_control_flow() {
+        let mut vm = VM::new();
+        let module = format::read(&super::build("1", &mut vm, BuildOptions::NONE).unwrap()).unwrap();
+
+        // code the builder doesn't emit, but that still passes the verifier
+        let exec_code = |code: Vec<u8>| {
+            let mut module = module.clone();
+            module.code = code;
+            let mut vm = VM::new();
+            vm.exec(vm::get_program(format::write(&module)).unwrap(), false)
+        };
+
+        match exec_code(vec![vm::u(vm::OPCode::PUSH_NIL), vm::u(vm::OPCode::RETURN), vm::u(vm::OPCode::END)]) {
+            Err(VMExecError::ReturnWithoutCall(None)) => {},
+            res => panic!("expected return without a call, got {:?}", res)
+        }
+        match exec_code(vec![vm::u(vm::OPCode::PUSH_NIL), vm::u(vm::OPCode::CONTINUE), vm::u(vm::OPCode::END)]) {
+            Err(VMExecError::ContinueWithoutLoop(None)) => {},
+            res => panic!("expected continue without a loop, got {:?}", res)
+        }
+
+        // ITER_NEXT without ITER before it
+        let iter_next = |stack_len: usize| {
+            let mut code = vec![vm::u(vm::OPCode::PUSH_NIL); stack_len];
+            code.push(vm::u(vm::OPCode::ITER_NEXT));
+            for _ in 0..2 {
+                code.push(vm::u(vm::OPCode::I32));
+                code.extend_from_slice(&0i32.to_le_bytes());
+            }
+            code.push(vm::u(vm::OPCode::END));
+            exec_code(code)
+        };
+        match iter_next(2) {
+            Err(VMExecError::NotAnIterator(Value::Literal(Literal::Nil), None)) => {},
+            res => panic!("expected not an iterator, got {:?}", res)
+        }
+        match iter_next(1) {
+            Err(VMExecError::StackUnderflow(2, 1, None)) => {},
+            res => panic!("expected stack underflow, got {:?}", res)
+        }
+
+        let mut dup = vec![vm::u(vm::OPCode::PUSH_NIL), vm::u(vm::OPCode::DUP), vm::u(vm::OPCode::I32)];
+        dup.extend_from_slice(&2i32.to_le_bytes());
+        dup.push(vm::u(vm::OPCode::END));
+        match exec_code(dup) {
+            Err(VMExecError::StackUnderflow(2, 1, None)) => {},
+            res => panic!("expected stack underflow, got {:?}", res)
+        }
+
+        match exec_code(vec![vm::u(vm::OPCode::POP_LOOP), vm::u(vm::OPCode::END)]) {
+            Err(VMExecError::JumpStackUnderflow(vm::OPCode::POP_LOOP, None)) => {},
+            res => panic!("expected jump stack underflow, got {:?}", res)
+        }
+        for &code in &[vm::OPCode::SCOPE_FORWARD, vm::OPCode::SCOPE_BACK] {
+            match exec_code(vec![vm::u(code), vm::u(vm::OPCode::END)]) {
+                Err(VMExecError::ScopeOutOfBounds(found, 0, 1)) => assert_eq!(found, code),
+                res => panic!("expected scope out of bounds for {:?}, got {:?}", code, res)
+            }
+        }
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let query = "
+            let depth = fn(n) {
+                if n == 0 { 0 } else { 1 + depth(n - 1) }
+            };
+            depth(5000)
+        ";
+        assert_eq!(run_all(query), "5000");
+
+        let mut vm = VM::new();
+        vm.vm_exec = VMExec::builder().max_depth(100).build();
+        let instructions = super::build(query, &mut vm, BuildOptions::DEBUG).unwrap();
+        match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+            Err(LangErr::VMExecErr(VMExecError::StackOverflow(100, trace))) => {
+                assert!(trace.len() > 1 && trace.len() < 100);
+                assert!(trace.iter().all(|site| site.func == trace[0].func && site.pos.is_some()));
+            },
+            res => panic!("expected stack overflow, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn stack_trace() {
+        let query = "let inner = fn(x) { x + nope };\nlet outer = fn() { inner(1) };\nlet S = struct { let v = outer(); };\nlet s = new S();";
+        let mut vm = VM::new();
+        let instructions = super::build(query, &mut vm, BuildOptions::DEBUG | BuildOptions::CODE).unwrap();
+        assert!(super::exec(vm::get_program(instructions).unwrap(), &mut vm, false).is_err());
+
+        let trace: Vec<(&str, u32)> = vm.vm_exec.trace().iter()
+            .map(|site| (site.name.as_ref().unwrap().as_str(), vm.vm_exec.spans[site.pos.unwrap() as usize].line))
+            .collect();
+        assert_eq!(trace, vec![("inner", 2), ("outer", 3), ("S", 4)]);
+
+        // finished calls leave nothing behind
+        let mut vm = VM::new();
+        let instructions = super::build("let f = fn() { 1 }; let S = struct {}; f(); let s = new S(); nope", &mut vm, BuildOptions::DEBUG).unwrap();
+        assert!(super::exec(vm::get_program(instructions).unwrap(), &mut vm, false).is_err());
+        assert!(vm.vm_exec.trace().is_empty());
+    }
+
+    #[test]
+    fn spans() {
+        let lexed = lexer::lex("let abc = \"x\";\n  abc >= 10").unwrap();
+        let spans: Vec<(u32, u32, u32)> = lexed.iter()
+            .map(|lexed| match *lexed {
+                lexer::Lexed::Literal(_, span) | lexer::Lexed::Operator(_, span) | lexer::Lexed::Identifier(_, span) => span
+            })
+            .map(|span| (span.line, span.col, span.width()))
+            .collect();
+        assert_eq!(spans[..7].to_vec(), vec![(1, 1, 3), (1, 5, 3), (1, 9, 1), (1, 11, 3), (1, 14, 1), (2, 3, 3), (2, 7, 2)]);
+
+        match lexer::lex("let s = \"a\\qb\";") {
+            Err(lexer::LexErr::UnknownEscapeSequence('q', span)) => assert_eq!((span.col, span.width()), (11, 2)),
+            res => panic!("expected unknown escape sequence, got {:?}", res)
+        }
+
+        match lexer::lex("let big = 1 + 9223372036854775808;") {
+            Err(lexer::LexErr::IntegerOverflow(ref literal, span)) => {
+                assert_eq!(literal, "9223372036854775808");
+                assert_eq!((span.col, span.width()), (15, 19));
+            },
+            res => panic!("expected integer overflow, got {:?}", res)
+        }
+        assert!(lexer::lex("9223372036854775807 + 9223372036854775808.0 + 1e30").is_ok());
+
+        // in the shell, every query is its own source
+        let mut vm = VM::new();
+        let instructions = super::build_file("<shell>", "let f = fn() { 1 + nope };", &mut vm, BuildOptions::DEBUG | BuildOptions::CODE).unwrap();
+        super::exec(vm::get_program(instructions).unwrap(), &mut vm, false).unwrap();
+        let instructions = super::build_file("<shell>", "\nf()", &mut vm, BuildOptions::DEBUG | BuildOptions::CODE).unwrap();
+        match super::exec(vm::get_program(instructions).unwrap(), &mut vm, true) {
+            Err(LangErr::VMExecErr(VMExecError::VariableNotDefined(_, pos))) => {
+                let span = vm.vm_exec.spans[pos as usize];
+                assert_eq!((span.file, span.line, span.col, span.width()), (0, 1, 20, 4));
+                assert_eq!(vm.vm_exec.spans[vm.vm_exec.trace()[0].pos.unwrap() as usize].file, 1);
+                assert_eq!(vm.vm_exec.sources[1].name, "<shell>");
+            },
+            res => panic!("expected variable not defined, got {:?}", res)
+        }
+
+        // arguments, elements and fields point at themselves, not at their container
+        let queries = [
+            ("print(1, nope)", (10, 4)), ("[1, nope]", (5, 4)), ("let m = {\"a\": nope}", (15, 4)),
+            ("let a = [1]; a[nope]", (16, 4)), ("let s = struct {}; let i = new s(); i.nope", (39, 4))
+        ];
+        for &(query, expected) in &queries {
+            let mut vm = VM::new();
+            let instructions = super::build(query, &mut vm, BuildOptions::DEBUG).unwrap();
+            match super::exec(vm::get_program(instructions).unwrap(), &mut vm, false) {
+                Err(LangErr::VMExecErr(err)) => {
+                    let span = vm.vm_exec.spans[err.pos().unwrap() as usize];
+                    assert_eq!((span.col, span.width()), expected, "span of {}", query);
+                },
+                res => panic!("expected an error for {}, got {:?}", query, res)
+            }
+        }
+    }
+
+    #[test]
+    fn json_errors() {
+        let mut vm = VM::new();
+        vm.error_format = super::ErrorFormat::Json;
+        let query = "let s = 1;\nlet t = \"\\q\";";
+        let err = super::build_file("a.lang", query, &mut vm, BuildOptions::DEBUG).unwrap_err();
+        assert_eq!(err.to_string(), "unknown escape sequence: \"q\"");
+        let source: &dyn std::error::Error = &err;
+        assert_eq!(source.to_string(), err.to_string());
+        assert_eq!(
+            handle_err::err_json(&err, &[Source::new("a.lang", query)], &[], &[]),
+            "{\"kind\": \"LexErr::UnknownEscapeSequence\", \"message\": \"unknown escape sequence: \\\"q\\\"\", \"file\": \"a.lang\", \"line\": 2, \"column\": 10, \"length\": 2, \"notes\": []}"
+        );
+
+        // release builds have no positions
+        let instructions = super::build("1 ~/ 0", &mut vm, BuildOptions::NONE).unwrap();
+        let err = super::exec(vm::get_program(instructions).unwrap(), &mut vm, false).unwrap_err();
+        assert_eq!(
+            handle_err::err_json(&err, &vm.vm_exec.sources, &vm.vm_exec.spans, &[String::from("note")]),
+            "{\"kind\": \"VMExecErr::DivisionByZero\", \"message\": \"integer division by zero in operation INT_DIVIDE\", \"file\": null, \"line\": null, \"column\": null, \"length\": null, \"notes\": [\"note\"]}"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        fn error_lines(query: &str) -> Vec<u32> {
+            match super::build(query, &mut VM::new(), BuildOptions::NONE) {
+                Err(LangErr::ParserErr(errs)) => errs.iter().map(|err| err.span().unwrap().line).collect(),
+                res => panic!("expected parser errors, got {:?}", res)
+            }
+        }
+
+        // tokens that can't follow each other
+        assert_eq!(error_lines("let a = 1 +;\nlet b = 2;\nlet c = (b * ];\nlet d = fn() {\n  a +;\n};"), vec![1, 3, 5]);
+        // statements that can't be parsed, inside and outside of blocks
+        assert_eq!(error_lines("let m = {\"a\", 1};\nlet f = fn() {\n  let n = {\"b\", 2};\n  3\n};\nlet k = {\"c\", 3};\nf()"), vec![1, 3, 6]);
+        assert_eq!(error_lines("let f = fn() {\n  1\n"), vec![3]);
+    }
 }
@@ -21,12 +21,15 @@ fn shell(mut vm: &mut VM, options: BuildOptions) {
 
     query.pop().unwrap();
 
-    let instructions = match lang::build(&query, &mut vm, options) {
+    let instructions = match lang::build_file("<shell>", &query, &mut vm, options) {
       Ok(program) => program,
       Err(_) => continue
     };
 
-    let program = vm::get_program(instructions);
+    let program = match vm::get_program(instructions) {
+      Ok(program) => program,
+      Err(_) => continue
+    };
 
     match lang::exec(program, &mut vm, true) {
       Ok(res) => println!("{}", res),
@@ -35,6 +38,35 @@ fn shell(mut vm: &mut VM, options: BuildOptions) {
   }
 }
 
+fn read_program(name: &str) -> vm::Program {
+  let mut bytes = vec![];
+  let f = File::open(name).unwrap();
+  for byte in f.bytes() {
+    bytes.push(byte.unwrap());
+  }
+
+  match vm::get_program(bytes) {
+    Ok(program) => program,
+    Err(vm::FormatErr(vm::format::FormatError::VersionMismatch(found, expected))) => {
+      println!("{:?} was built for bytecode version {}, this version runs {}, rebuild it", name, found, expected);
+      std::process::exit(-1);
+    },
+    Err(err) => {
+      println!("invalid program {:?}: {:?}", name, err);
+      std::process::exit(-1);
+    }
+  }
+}
+
+// --error-format=text or --error-format=json
+fn error_format(arg: &str) -> lang::ErrorFormat {
+  match &arg["--error-format=".len()..] {
+    "text" => lang::ErrorFormat::Text,
+    "json" => lang::ErrorFormat::Json,
+    format => panic!("unknown error format: {}, use text or json", format)
+  }
+}
+
 fn main() {
   let mut vm = VM::new();
 
@@ -67,6 +99,7 @@ fn main() {
             },
             "--release" => release = true,
             "--optimized" => optimized = true,
+            arg if arg.starts_with("--error-format=") => vm.error_format = error_format(arg),
             _ => {
               panic!(format!("unknown option: {}", args[i]))
             }
@@ -89,14 +122,16 @@ fn main() {
         f.read_to_string(&mut contents)
           .expect("something went wrong reading the file");
 
-        match lang::build(&contents, &mut vm, options) {
+        match lang::build_file(name, &contents, &mut vm, options) {
           Ok(instructions) => {
             let mut file = File::create(output).unwrap();
             file.write_all(&instructions).unwrap();
             println!("build successful, program written to: {:?}", output);
           },
           Err(_) => {
-            println!("build error");
+            if vm.error_format == lang::ErrorFormat::Text {
+              println!("build error");
+            }
             std::process::exit(-1);
           }
         };
@@ -111,6 +146,7 @@ fn main() {
           }
           match args[i].as_ref() {
             "--shell" => shell_after = true,
+            arg if arg.starts_with("--error-format=") => vm.error_format = error_format(arg),
             _ => {
               panic!(format!("unknown option: {}", args[i]))
             }
@@ -118,14 +154,7 @@ fn main() {
           i += 1;
         }
         
-        let program = {
-          let mut bytes = vec![];
-          let mut f = File::open(name).unwrap();
-          for byte in f.bytes() {
-            bytes.push(byte.unwrap());
-          }
-          vm::get_program(bytes)
-        };
+        let program = read_program(name);
 
         match lang::exec(program, &mut vm, false) {
           Ok(res) => println!("{}", res),
@@ -145,6 +174,10 @@ fn main() {
 
         std::process::exit(0);
       },
+      "disasm" => {
+        let program = read_program(name);
+        print!("{}", vm::disasm::disassemble(&program));
+      },
       _ => {}
     }
   } else {
@@ -6,48 +6,87 @@ use lexer::Lexed;
 use lexer::Literal;
 use lexer::Token;
 use lexer::Token::*;
+use span::Span;
 
 // not used
 // type Program<'a> = Vec<Box<Declaration<'a>>>;
 
 #[derive(Debug)]
 pub enum Declaration {
-  Statement(Box<Statement>, i32),
-  // FunctionDecl(Box<FunctionDecl>, i32),
+  Statement(Box<Statement>, Span),
+  // FunctionDecl(Box<FunctionDecl>, Span),
 }
 
 #[derive(Debug)]
 pub enum Statement {
   // expression, is statement (semicolon), pos
-  ExpressionStmt(Box<Expression>, bool, i32),
-  // BlockStmt(Vec<Box<Declaration<'a>>>, i32),
+  ExpressionStmt(Box<Expression>, bool, Span),
+  // expression (nil if none), pos
+  Return(Option<Box<Expression>>, Span),
+  // expression (nil if none), pos
+  Break(Option<Box<Expression>>, Span),
+  Continue(Span),
+  // BlockStmt(Vec<Box<Declaration<'a>>>, Span),
 }
 
 #[derive(Debug)]
 pub enum Expression {
-  Binary(Box<Expression>, (Token, i32), Box<Expression>),
-  Primary(Primary, i32),
+  Binary(Box<Expression>, (Token, Span), Box<Expression>),
+  Primary(Primary, Span),
 
   // expr, body, else_body, expr_pos, pos
-  IfExpr(Box<Expression>, Vec<Box<Declaration>>, Vec<Box<Declaration>>, i32, i32),
+  IfExpr(Box<Expression>, Vec<Box<Declaration>>, Vec<Box<Declaration>>, Span, Span),
 
   // expr, body, expr_pos, pos
-  WhileExpr(Box<Expression>, Vec<Box<Declaration>>, i32, i32),
+  WhileExpr(Box<Expression>, Vec<Box<Declaration>>, Span, Span),
 
-  // body, pos
-  StructExpr(Vec<Box<Declaration>>, i32),
+  // identifier, iterable expr, body, expr_pos, pos
+  ForExpr(String, Box<Expression>, Vec<Box<Declaration>>, Span, Span),
+
+  // body, struct it extends, pos
+  StructExpr(Vec<Box<Declaration>>, Option<Box<Expression>>, Span),
 
   // struct expression, arguments for __init__, pos
-  NewExpr(Box<Expression>, Vec<Box<Expression>>, i32),
+  NewExpr(Box<Expression>, Vec<Box<Expression>>, Span),
 
   // scope, lookup (identifier), pos
-  DotExpr(Box<Expression>, String, i32),
+  DotExpr(Box<Expression>, String, Span),
 
   // parameters, body
-  FunctionExpr(Vec<String>, Vec<Box<Declaration>>, i32),
+  FunctionExpr(Vec<String>, Vec<Box<Declaration>>, Span),
 
   // function expression, arguments
-  FunctionCallExpr(Box<Expression>, Vec<Box<Expression>>, i32)
+  FunctionCallExpr(Box<Expression>, Vec<Box<Expression>>, Span),
+
+  // elements, pos
+  ArrayExpr(Vec<Box<Expression>>, Span),
+
+  // (key, value) entries, pos
+  MapExpr(Vec<(Box<Expression>, Box<Expression>)>, Span),
+
+  // collection, index, pos
+  IndexExpr(Box<Expression>, Box<Expression>, Span)
+}
+
+impl Expression {
+  /// The span that errors and debug positions of the expression point at.
+  pub fn span(&self) -> Span {
+    match *self {
+      Expression::Binary(_, (_, pos), _) |
+      Expression::Primary(_, pos) |
+      Expression::IfExpr(_, _, _, _, pos) |
+      Expression::WhileExpr(_, _, _, pos) |
+      Expression::ForExpr(_, _, _, _, pos) |
+      Expression::StructExpr(_, _, pos) |
+      Expression::NewExpr(_, _, pos) |
+      Expression::DotExpr(_, _, pos) |
+      Expression::FunctionExpr(_, _, pos) |
+      Expression::FunctionCallExpr(_, _, pos) |
+      Expression::ArrayExpr(_, pos) |
+      Expression::MapExpr(_, pos) |
+      Expression::IndexExpr(_, _, pos) => pos
+    }
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +102,8 @@ pub enum Primary {
 pub struct Grammar {
   lexed: Vec<Lexed>,
   current: usize,
+  // errors of the declarations that were skipped
+  errors: Vec<ParserErr>,
 }
 
 impl<'a> Grammar {
@@ -70,10 +111,11 @@ impl<'a> Grammar {
     Grammar {
       lexed,
       current: 0,
+      errors: Vec::new(),
     }
   }
 
-  fn do_match(&mut self, tokens: &[Token]) -> Option<(Token, i32)> {
+  fn do_match(&mut self, tokens: &[Token]) -> Option<(Token, Span)> {
     if self.lexed.len() <= 0 { // ?
       return None;
     }
@@ -96,11 +138,18 @@ impl<'a> Grammar {
     None
   }
 
+  fn is_next(&self, tokens: &[Token]) -> bool {
+    match self.lexed[self.current] {
+      Lexed::Operator(token, _) => tokens.contains(&token),
+      _ => false
+    }
+  }
+
   fn advance(&mut self) {
     self.current += 1;
   }
 
-  fn get_pos(&self) -> i32 {
+  fn get_pos(&self) -> Span {
     match self.lexed[self.current] {
       Lexed::Identifier(_, pos) => pos,
       Lexed::Literal(_, pos) => pos,
@@ -108,15 +157,71 @@ impl<'a> Grammar {
     }
   }
 
-  pub fn program(&mut self) -> Result<Vec<Box<Declaration>>, ParserErr> {
+  /// Parses all declarations, skipping the ones that fail so the errors of all of them can be reported.
+  pub fn program(&mut self) -> Result<Vec<Box<Declaration>>, Vec<ParserErr>> {
     let mut declarations: Vec<Box<Declaration>> = vec![];
     while self.current < self.lexed.len() - 1 {
-      declarations.push(Box::new(self.declaration()?));
+      let start = self.current;
+      match self.declaration() {
+        Ok(decl) => declarations.push(Box::new(decl)),
+        Err(err) => {
+          self.errors.push(err);
+          self.synchronize(start);
+          // a stray close brace, there's no block for it to end
+          if self.current == start {
+            self.advance();
+          }
+        }
+      }
+    }
+
+    if self.errors.is_empty() {
+      Ok(declarations)
+    } else {
+      Err(std::mem::take(&mut self.errors))
+    }
+  }
+
+  // the declarations of a block up to and including its close brace, the open brace is already matched
+  fn block(&mut self) -> Result<Vec<Box<Declaration>>, ParserErr> {
+    let mut decls: Vec<Box<Declaration>> = vec![];
+    while let None = self.do_match(&[BraceClose]) {
+      if self.is_next(&[EOF]) {
+        return Err(ParserErr::UnexpectedToken(self.get_pos(), EOF, vec![BraceClose], false, false));
+      }
+
+      let start = self.current;
+      match self.declaration() {
+        Ok(decl) => decls.push(Box::new(decl)),
+        Err(err) => {
+          self.errors.push(err);
+          self.synchronize(start);
+        }
+      }
     }
 
-    Ok(
-      declarations
-    )
+    Ok(decls)
+  }
+
+  // after the declaration starting at `start` failed, moves to past the semicolon ending it,
+  // or to the close brace of the block it's in
+  fn synchronize(&mut self, start: usize) {
+    let mut depth = 0;
+    let mut i = start;
+    while i < self.lexed.len() - 1 {
+      match self.lexed[i] {
+        Lexed::Operator(BraceOpen, _) => depth += 1,
+        Lexed::Operator(BraceClose, _) if depth == 0 => break,
+        Lexed::Operator(BraceClose, _) => depth -= 1,
+        Lexed::Operator(SemiColon, _) if depth == 0 => {
+          i += 1;
+          break;
+        },
+        _ => {}
+      }
+      i += 1;
+    }
+    self.current = i;
   }
 
   fn declaration(&mut self) -> Result<Declaration, ParserErr> {
@@ -128,6 +233,33 @@ impl<'a> Grammar {
   fn statement(&mut self) -> Result<Statement, ParserErr> {
     let pos = self.get_pos();
 
+    // return statement
+    if let Some((_, pos)) = self.do_match(&[Return]) {
+      let expr = if self.is_next(&[SemiColon, BraceClose, EOF]) {
+        None
+      } else {
+        Some(Box::new(self.expression()?))
+      };
+      self.do_match(&[SemiColon]);
+      return Ok(Statement::Return(expr, pos));
+    }
+
+    // loop control
+    if let Some((_, pos)) = self.do_match(&[Break]) {
+      let expr = if self.is_next(&[SemiColon, BraceClose, EOF]) {
+        None
+      } else {
+        Some(Box::new(self.expression()?))
+      };
+      self.do_match(&[SemiColon]);
+      return Ok(Statement::Break(expr, pos));
+    }
+
+    if let Some((_, pos)) = self.do_match(&[Continue]) {
+      self.do_match(&[SemiColon]);
+      return Ok(Statement::Continue(pos));
+    }
+
     // expression statement
     let expr = self.expression()?;
     if let Some((_operator, _pos)) = self.do_match(&[SemiColon]) {
@@ -161,7 +293,8 @@ impl<'a> Grammar {
   fn assign(&mut self) -> Result<Expression, ParserErr> {
     let mut expr = self.func_expr()?;
 
-    while let Some((operator, pos)) = self.do_match(&[Equals]) {
+    // compound assignments, `a += 1`, are built from the operator
+    while let Some((operator, pos)) = self.do_match(&[Equals, PlusEquals, MinusEquals, AsterixEquals, SlashEquals]) {
       let right = self.expression()?;
       expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
     }
@@ -177,8 +310,6 @@ impl<'a> Grammar {
           if let Some(_) = self.do_match(&[Identifier]) {
             if let Lexed::Identifier(ref name, _) = self.lexed[self.current - 1] { // it must be
               parameters.push(name.to_string());
-            } else {
-              println!("function parsing this should not happen");
             }
 
             if let None = self.do_match(&[Comma]) {
@@ -191,16 +322,12 @@ impl<'a> Grammar {
         }
 
         if let None = self.do_match(&[ParClose]) {
-          println!("here");
           return Err(ParserErr::MismatchedParenthesis(par_pos));
         }
       }
 
       if let Some(_) = self.do_match(&[BraceOpen]) {
-        let mut body: Vec<Box<Declaration>> = Vec::new();
-        while let None = self.do_match(&[BraceClose]) {
-          body.push(Box::new(self.declaration()?));
-        }
+        let body = self.block()?;
 
         let fexpr = Expression::FunctionExpr(parameters, body, func_pos);
         return Ok(fexpr);
@@ -209,7 +336,35 @@ impl<'a> Grammar {
       }
     }
 
-    Ok(self.while_expr()?)
+    Ok(self.for_expr()?)
+  }
+
+  fn for_expr(&mut self) -> Result<Expression, ParserErr> {
+    if let Some((_, pos)) = self.do_match(&[For]) {
+      let identifier = match self.do_match(&[Identifier]) {
+        Some(_) => match self.lexed[self.current - 1] {
+          Lexed::Identifier(ref name, _) => name.to_string(),
+          _ => return Err(ParserErr::ExpectedIdentifier(pos))
+        },
+        None => return Err(ParserErr::ExpectedIdentifier(self.get_pos()))
+      };
+
+      if let None = self.do_match(&[In]) {
+        return Err(ParserErr::ExpectedIn(self.get_pos()));
+      }
+
+      let expr_pos = self.get_pos();
+      let expr = self.expression()?;
+      if let None = self.do_match(&[BraceOpen]) {
+        return Err(ParserErr::ExpectedBraceOpen(pos));
+      }
+
+      let decls = self.block()?;
+
+      return Ok(Expression::ForExpr(identifier, Box::new(expr), decls, expr_pos, pos));
+    }
+
+    self.while_expr()
   }
 
   fn while_expr(&mut self) -> Result<Expression, ParserErr> {
@@ -223,11 +378,7 @@ impl<'a> Grammar {
         return Err(ParserErr::ExpectedBraceOpen(pos));
       }
 
-      let mut decls: Vec<Box<Declaration>> = vec![];
-
-      while let None = self.do_match(&[BraceClose]) {
-        decls.push(Box::new(self.declaration()?));
-      }
+      let decls = self.block()?;
 
       return Ok(Expression::WhileExpr(Box::new(expr), decls, expr_pos, pos));
     }
@@ -246,21 +397,15 @@ impl<'a> Grammar {
         return Err(ParserErr::ExpectedBraceOpen(pos));
       }
 
-      let mut decls: Vec<Box<Declaration>> = vec![];
+      let decls = self.block()?;
       let mut else_decls: Vec<Box<Declaration>> = vec![];
 
-      while let None = self.do_match(&[BraceClose]) {
-        decls.push(Box::new(self.declaration()?));
-      }
-
       if let Some((operator, pos)) = self.do_match(&[Else]) {
         if let None = self.do_match(&[BraceOpen]) {
           return Err(ParserErr::ExpectedBraceOpen(pos));
         }
 
-        while let None = self.do_match(&[BraceClose]) {
-          else_decls.push(Box::new(self.declaration()?));
-        }
+        else_decls = self.block()?;
       }
 
       return Ok(Expression::IfExpr(Box::new(expr), decls, else_decls, expr_pos, pos));
@@ -271,22 +416,49 @@ impl<'a> Grammar {
 
   fn struct_def(&mut self) -> Result<Expression, ParserErr> {
     if let Some((_, pos)) = self.do_match(&[Struct]) {
+      let base = match self.do_match(&[Extends]) {
+        Some(_) => Some(Box::new(self.dot_expr()?)),
+        None => None
+      };
+
       if let None = self.do_match(&[BraceOpen]) {
         return Err(ParserErr::ExpectedBraceOpen(pos));
       }
 
       let lexed = lexer::lex("let __init__ = fn(){};").unwrap();
       let mut this = Self::new(lexed);
-      let mut decls: Vec<Box<Declaration>> = this.program()?;
+      let mut decls: Vec<Box<Declaration>> = match this.program() {
+        Ok(decls) => decls,
+        Err(_) => return Err(ParserErr::GrammarError(0))
+      };
+      decls.append(&mut self.block()?);
 
-      while let None = self.do_match(&[BraceClose]) {
-        decls.push(Box::new(self.declaration()?));
-      }
+      return Ok(Expression::StructExpr(decls, base, pos));
+    }
+
+    self.logic_or()
+  }
+
+  fn logic_or(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.logic_and()?;
+
+    while let Some((operator, pos)) = self.do_match(&[Or]) {
+      let right = self.logic_and()?;
+      expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
+    }
+
+    Ok(expr)
+  }
 
-      return Ok(Expression::StructExpr(decls, pos));
+  fn logic_and(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.equality()?;
+
+    while let Some((operator, pos)) = self.do_match(&[And]) {
+      let right = self.equality()?;
+      expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
     }
 
-    self.equality()
+    Ok(expr)
   }
 
   fn equality(&mut self) -> Result<Expression, ParserErr> {
@@ -301,42 +473,86 @@ impl<'a> Grammar {
   }
 
   fn comparison(&mut self) -> Result<Expression, ParserErr> {
-    let mut expr = self.addition()?;
+    let mut expr = self.range()?;
 
     while let Some((operator, pos)) = self.do_match(&[Gt, Lt, GtOrEquals, LtOrEquals]) {
-      let right = self.addition()?;
+      let right = self.range()?;
       expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
     }
 
     Ok(expr)
   }
 
-  fn addition(&mut self) -> Result<Expression, ParserErr> {
-    let mut expr = self.multiplication()?;
+  fn range(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.bit_or()?;
 
-    while let Some((operator, pos)) = self.do_match(&[Plus, Minus]) {
-      let right = self.multiplication()?;
+    if let Some((operator, pos)) = self.do_match(&[DotDot]) {
+      let right = self.bit_or()?;
       expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
     }
 
     Ok(expr)
   }
 
-  fn multiplication(&mut self) -> Result<Expression, ParserErr> {
-    let mut expr = self.raise()?;
+  fn bit_or(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.bit_xor()?;
 
-    while let Some((operator, pos)) = self.do_match(&[Asterix, Slash]) {
-      let right = self.raise()?;
+    while let Some((operator, pos)) = self.do_match(&[Pipe]) {
+      let right = self.bit_xor()?;
       expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
     }
 
     Ok(expr)
   }
 
-  fn raise(&mut self) -> Result<Expression, ParserErr> {
+  fn bit_xor(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.bit_and()?;
+
+    while let Some((operator, pos)) = self.do_match(&[Caret]) {
+      let right = self.bit_and()?;
+      expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
+    }
+
+    Ok(expr)
+  }
+
+  fn bit_and(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.shift()?;
+
+    while let Some((operator, pos)) = self.do_match(&[Ampersand]) {
+      let right = self.shift()?;
+      expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
+    }
+
+    Ok(expr)
+  }
+
+  fn shift(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.addition()?;
+
+    while let Some((operator, pos)) = self.do_match(&[DoubleLt, DoubleGt]) {
+      let right = self.addition()?;
+      expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
+    }
+
+    Ok(expr)
+  }
+
+  fn addition(&mut self) -> Result<Expression, ParserErr> {
+    let mut expr = self.multiplication()?;
+
+    while let Some((operator, pos)) = self.do_match(&[Plus, Minus]) {
+      let right = self.multiplication()?;
+      expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
+    }
+
+    Ok(expr)
+  }
+
+  fn multiplication(&mut self) -> Result<Expression, ParserErr> {
     let mut expr = self.unary()?;
 
-    while let Some((operator, pos)) = self.do_match(&[DoubleAsterix]) {
+    while let Some((operator, pos)) = self.do_match(&[Asterix, Slash, TildeSlash, Percent]) {
       let right = self.unary()?;
       expr = Expression::Binary(Box::new(expr), (operator, pos), Box::new(right));
     }
@@ -347,10 +563,23 @@ impl<'a> Grammar {
   fn unary(&mut self) -> Result<Expression, ParserErr> {
     if let Some((operator, pos)) = self.do_match(&[Bang, Minus]) {
       let right = self.unary()?;
-      return Ok(Expression::Binary(Box::new(Expression::Primary(Primary::Literal(lexer::Literal::Num(0 as f64)), pos)), (operator, pos), Box::new(right)));
+      return Ok(Expression::Binary(Box::new(Expression::Primary(Primary::Literal(lexer::Literal::Int(0)), pos)), (operator, pos), Box::new(right)));
+    }
+
+    Ok(self.raise()?)
+  }
+
+  // binds tighter than a unary operator on its left, `-2 ** 2` is -4,
+  // and is right associative, `2 ** 3 ** 2` is 2 ** 9
+  fn raise(&mut self) -> Result<Expression, ParserErr> {
+    let expr = self.new_expr()?;
+
+    if let Some((operator, pos)) = self.do_match(&[DoubleAsterix]) {
+      let right = self.unary()?;
+      return Ok(Expression::Binary(Box::new(expr), (operator, pos), Box::new(right)));
     }
 
-    Ok(self.new_expr()?)
+    Ok(expr)
   }
 
   fn new_expr(&mut self) -> Result<Expression, ParserErr> {
@@ -383,7 +612,16 @@ impl<'a> Grammar {
   fn func_call_expr(&mut self) -> Result<Expression, ParserErr> {
     let mut expr = self.dot_expr()?;
 
-    while let Some((_, call_pos)) = self.do_match(&[Token::ParOpen]) {
+    while let Some((token, pos)) = self.do_match(&[Token::ParOpen, Token::BracketOpen]) {
+      if token == Token::BracketOpen {
+        let index = self.expression()?;
+        if let None = self.do_match(&[Token::BracketClose]) {
+          return Err(ParserErr::MismatchedBrackets(pos));
+        }
+        expr = Expression::IndexExpr(Box::new(expr), Box::new(index), pos);
+        continue;
+      }
+
       let mut args: Vec<Box<Expression>> = Vec::new();
 
       while let None = self.do_match(&[Token::ParClose]) {
@@ -397,7 +635,7 @@ impl<'a> Grammar {
           }
         }
       }
-      expr = Expression::FunctionCallExpr(Box::new(expr), args, call_pos);
+      expr = Expression::FunctionCallExpr(Box::new(expr), args, pos);
     }
 
     Ok(expr)
@@ -406,11 +644,12 @@ impl<'a> Grammar {
   fn dot_expr(&mut self) -> Result<Expression, ParserErr> {
     let mut expr = self.primary()?;
 
-    while let Some((_, pos)) = self.do_match(&[Dot]) {
+    while self.do_match(&[Dot]).is_some() {
       let right = self.primary()?;
-      let right = match right {
-        Expression::Primary(ref primary, _) => match primary {
-          &Primary::Identifier(ref identifier) => identifier,
+      // errors about the field point at its name
+      let (right, pos) = match right {
+        Expression::Primary(ref primary, pos) => match primary {
+          &Primary::Identifier(ref identifier) => (identifier, pos),
           _ => return Err(ParserErr::Temp(69))
         }
         _ => return Err(ParserErr::Temp(68))
@@ -434,31 +673,72 @@ impl<'a> Grammar {
 
     let matched = self.do_match(&[Token::ParOpen]);
     if let Some((_, pos)) = matched {
-      let expr = self.expression();
+      let expr = self.expression()?;
       if let Some(_) = self.do_match(&[Token::ParClose]) {
-        return expr;
+        return Ok(expr);
       } else {
-        println!("{:?}", expr);
         return Err(ParserErr::MismatchedParenthesis(pos));
       }
     }
 
+    if let Some((_, pos)) = self.do_match(&[Token::BracketOpen]) {
+      let mut elements: Vec<Box<Expression>> = Vec::new();
+
+      while let None = self.do_match(&[Token::BracketClose]) {
+        elements.push(Box::new(self.expression()?));
+
+        if let None = self.do_match(&[Token::Comma]) {
+          if let None = self.do_match(&[Token::BracketClose]) {
+            return Err(ParserErr::MismatchedBrackets(pos));
+          } else {
+            break;
+          }
+        }
+      }
+      return Ok(Expression::ArrayExpr(elements, pos));
+    }
+
+    if let Some((_, pos)) = self.do_match(&[Token::BraceOpen]) {
+      let mut entries: Vec<(Box<Expression>, Box<Expression>)> = Vec::new();
+
+      while let None = self.do_match(&[Token::BraceClose]) {
+        let key = self.expression()?;
+        if let None = self.do_match(&[Token::Colon]) {
+          return Err(ParserErr::ExpectedColon(self.get_pos()));
+        }
+        let value = self.expression()?;
+        entries.push((Box::new(key), Box::new(value)));
+
+        if let None = self.do_match(&[Token::Comma]) {
+          if let None = self.do_match(&[Token::BraceClose]) {
+            return Err(ParserErr::ExpectedBraceClose(self.get_pos()));
+          } else {
+            break;
+          }
+        }
+      }
+      return Ok(Expression::MapExpr(entries, pos));
+    }
+
     if let Some((_, pos)) = self.do_match(&[Token::ParClose]) {
       self.current -= 1;
       return Ok(Expression::Primary(Primary::Literal(Literal::Nil), pos));
     }
 
-    if let Some((_, pos)) = self.do_match(&[Token::EOF]) {
+    let pos = self.get_pos();
+    if self.is_next(&[Token::EOF]) {
       return Err(ParserErr::UnexpectedToken(pos, Token::EOF, vec![], false, false))
     }
 
-    println!("last effort: {:?}", self.lexed[self.current]);
-
-    // last effort
-    let expr = self.expression()?;
-    return Ok(expr);
+    // an expression of a lower precedence in an operand, like `1 + if a { 2 } else { 3 }`
+    let starts = [Let, Func, For, While, If, Struct, Bang, Minus, New];
+    if self.is_next(&starts) {
+      return self.expression();
+    }
 
-    // println!("{:?}", self.lexed[self.current]);
-    // Err(ParserErr::GrammarError(5))
+    match self.lexed[self.current] {
+      Lexed::Operator(token, _) => Err(ParserErr::UnexpectedToken(pos, token, starts.to_vec(), true, true)),
+      _ => Err(ParserErr::GrammarError(0))
+    }
   }
 }
\ No newline at end of file
@@ -1,4 +1,7 @@
+use std::{error, fmt};
+
 use lexer::Token;
+use span::Span;
 
 // pub const generator: &'static str = "
 
@@ -6,21 +9,70 @@ use lexer::Token;
 
 #[derive(Debug)]
 pub enum ParserErr {
-  UnexpectedIdentifier(i32),
-  UnexpectedLiteral(i32),
+  UnexpectedIdentifier(Span),
+  UnexpectedLiteral(Span),
   // pos, unexpected_token, expected_tokens, expected_literal, expected_identifier
-  UnexpectedToken(i32, Token, Vec<Token>, bool, bool),
-  UnexpectedEndOfLine(i32),
+  UnexpectedToken(Span, Token, Vec<Token>, bool, bool),
+  UnexpectedEndOfLine(Span),
 
-  ExpectedSemiColon(i32),
-  ExpectedBraceOpen(i32),
-  ExpectedBraceClose(i32),
-  ExpectedParOpen(i32),
-  ExpectedIdentifier(i32),
-  ExpectedArrow(i32),
-  MismatchedParenthesis(i32),
+  ExpectedSemiColon(Span),
+  ExpectedBraceOpen(Span),
+  ExpectedBraceClose(Span),
+  ExpectedParOpen(Span),
+  ExpectedIdentifier(Span),
+  ExpectedArrow(Span),
+  ExpectedColon(Span),
+  ExpectedIn(Span),
+  MismatchedParenthesis(Span),
+  MismatchedBrackets(Span),
 
   GrammarError(i32),
   UnknownErr,
   Temp(i32)
-}
\ No newline at end of file
+}
+
+impl ParserErr {
+  pub fn span(&self) -> Option<Span> {
+    match *self {
+      ParserErr::UnexpectedIdentifier(span) |
+      ParserErr::UnexpectedLiteral(span) |
+      ParserErr::UnexpectedToken(span, ..) |
+      ParserErr::UnexpectedEndOfLine(span) |
+      ParserErr::ExpectedSemiColon(span) |
+      ParserErr::ExpectedBraceOpen(span) |
+      ParserErr::ExpectedBraceClose(span) |
+      ParserErr::ExpectedParOpen(span) |
+      ParserErr::ExpectedIdentifier(span) |
+      ParserErr::ExpectedArrow(span) |
+      ParserErr::ExpectedColon(span) |
+      ParserErr::ExpectedIn(span) |
+      ParserErr::MismatchedParenthesis(span) |
+      ParserErr::MismatchedBrackets(span) => Some(span),
+      ParserErr::GrammarError(_) | ParserErr::UnknownErr | ParserErr::Temp(_) => None
+    }
+  }
+}
+
+impl fmt::Display for ParserErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ParserErr::UnexpectedIdentifier(_) => write!(f, "unexpected identifier"),
+      ParserErr::UnexpectedLiteral(_) => write!(f, "unexpected literal"),
+      ParserErr::UnexpectedToken(_, token, ..) => write!(f, "unexpected token: \"{:#?}\"", token),
+      ParserErr::UnexpectedEndOfLine(_) => write!(f, "unexpected end of line"),
+      ParserErr::ExpectedSemiColon(_) => write!(f, "expected semicolon"),
+      ParserErr::ExpectedBraceOpen(_) => write!(f, "expected open brace for this expression"),
+      ParserErr::ExpectedBraceClose(_) => write!(f, "expected close brace"),
+      ParserErr::ExpectedParOpen(_) => write!(f, "expected open parenthesis"),
+      ParserErr::ExpectedIdentifier(_) => write!(f, "expected identifier"),
+      ParserErr::ExpectedArrow(_) => write!(f, "expected arrow for function expression"),
+      ParserErr::ExpectedColon(_) => write!(f, "expected colon after map key"),
+      ParserErr::ExpectedIn(_) => write!(f, "expected \"in\" after the loop variable"),
+      ParserErr::MismatchedParenthesis(_) => write!(f, "mismatched parenthesis"),
+      ParserErr::MismatchedBrackets(_) => write!(f, "mismatched brackets"),
+      _ => write!(f, "{:?}", self)
+    }
+  }
+}
+
+impl error::Error for ParserErr {}
\ No newline at end of file
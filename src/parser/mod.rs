@@ -13,8 +13,9 @@ use lexer::Token;
 use lexer::Token::*;
 use lexer::Lexed;
 
-/// Checks the instructions and checks if they're allowed
-pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
+/// Checks the instructions and checks if they're allowed.
+/// After an error, checking continues with the next statement.
+pub fn check(lexed: &Vec<Lexed>) -> Result<(), Vec<ParserErr>> {
   // let atokens: HashMap<&str, Token> = get_tokens();
 
   let mut allowed_literal = true;
@@ -24,33 +25,40 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
     New,
     Struct,
     ParOpen,
+    BracketOpen,
     LineBreak,
     Bang,
     Minus,
     EOF,
     If,
     While,
+    For,
     Func,
+    Return,
+    Break,
+    Continue,
   ];
 
+  let mut errors = Vec::new();
+
   let mut i = 0;
   let len = lexed.len();
   while i < len {
-    let t = &lexed[i];
+    let mut t = &lexed[i];
 
-    match t {
+    let err = match t {
       &Lexed::Literal(ref _literal, pos) => {
         if !allowed_literal {
-          return Err(ParserErr::UnexpectedLiteral(pos));
+          Some(ParserErr::UnexpectedLiteral(pos))
         } else {
-
+          None
         }
       },
       &Lexed::Identifier(ref _name, pos) => {
         if !allowed_identifier {
-          return Err(ParserErr::UnexpectedIdentifier(pos));
+          Some(ParserErr::UnexpectedIdentifier(pos))
         } else {
-
+          None
         }
       },
       &Lexed::Operator(token, pos) => {
@@ -62,11 +70,23 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
           // };
 
           match token {
-            Token::LineBreak => return Err(ParserErr::UnexpectedEndOfLine(pos)),
-            _ => return Err(ParserErr::UnexpectedToken(pos, token, allowed_operators, allowed_literal, allowed_identifier))
-          };
+            Token::LineBreak => Some(ParserErr::UnexpectedEndOfLine(pos)),
+            _ => Some(ParserErr::UnexpectedToken(pos, token, allowed_operators.clone(), allowed_literal, allowed_identifier))
+          }
+        } else {
+          None
         }
       }
+    };
+
+    if let Some(err) = err {
+      errors.push(err);
+
+      // skip the rest of the statement, the next one is checked from its semicolon
+      while i < len - 1 && !is_semicolon(&lexed[i]) {
+        i += 1;
+      }
+      t = &lexed[i];
     }
 
     allowed_operators.clear();
@@ -76,44 +96,56 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
     match t {
       &Lexed::Literal(ref _literal, _pos) => {
         allowed_operators = vec![
-          Plus, Minus, Asterix, DoubleAsterix, Slash, Dot, Comma, ParClose, SemiColon,
-          BraceOpen, BraceClose,
-          EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, EOF
+          Plus, Minus, Asterix, DoubleAsterix, Slash, TildeSlash, Percent, Dot, DotDot, Comma, ParClose, SemiColon,
+          Ampersand, Pipe, Caret, DoubleLt, DoubleGt,
+          BraceOpen, BraceClose, BracketOpen, BracketClose, Colon,
+          EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, And, Or, EOF
         ];
       },
       &Lexed::Identifier(ref _name, _pos) => {
         allowed_operators = vec![
-          Equals, Plus, Minus, Asterix, DoubleAsterix, Slash, Dot, Comma, ParClose, SemiColon, ParOpen,
-          BraceOpen, BraceClose,
-          EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, EOF
+          Equals, PlusEquals, MinusEquals, AsterixEquals, SlashEquals,
+          Plus, Minus, Asterix, DoubleAsterix, Slash, TildeSlash, Percent, Dot, DotDot, Comma, ParClose, SemiColon, ParOpen,
+          Ampersand, Pipe, Caret, DoubleLt, DoubleGt,
+          BraceOpen, BraceClose, BracketOpen, BracketClose, Colon, In,
+          EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, And, Or, EOF
         ];
       },
       &Lexed::Operator(token, _pos) => {
         match token {
-          Equals => {
+          Equals | PlusEquals | MinusEquals | AsterixEquals | SlashEquals => {
             allowed_identifier = true;
             allowed_literal = true;
             allowed_operators = vec![
-              ParOpen, Minus, Bang,
-              While, Let, New, Struct, If, Func
+              ParOpen, BracketOpen, BraceOpen, Minus, Bang,
+              While, For, Let, New, Struct, If, Func
             ];
           },
-          Plus | Minus | Asterix | Slash | Bang | DoubleAsterix => {
+          Plus | Minus | Asterix | Slash | TildeSlash | Percent | Bang | DoubleAsterix |
+          Ampersand | Pipe | Caret | DoubleLt | DoubleGt => {
             allowed_literal = true;
             allowed_identifier = true;
             allowed_operators = vec![
-              ParOpen, Minus, Bang,
-              While, If
+              ParOpen, BracketOpen, Minus, Bang,
+              While, For, If
             ];
           },
           EqualsEquals | BangEquals | LtOrEquals | GtOrEquals | Gt | Lt => {
             allowed_literal = true;
             allowed_identifier = true;
             allowed_operators = vec![
-              ParOpen,
-              If, While
+              ParOpen, BracketOpen, BraceOpen,
+              If, While, For
             ];
           }
+          And | Or => {
+            allowed_literal = true;
+            allowed_identifier = true;
+            allowed_operators = vec![
+              ParOpen, BracketOpen, Minus, Bang,
+              If, While, For
+            ];
+          },
           Dot => {
             allowed_identifier = true;
           },
@@ -121,21 +153,47 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
             allowed_identifier = true;
             allowed_literal = true;
             allowed_operators = vec![
-              Minus, Bang
+              Minus, Bang, ParOpen, BracketOpen, BraceOpen, Func
+            ];
+          },
+          Colon => {
+            allowed_identifier = true;
+            allowed_literal = true;
+            allowed_operators = vec![
+              Minus, Bang, ParOpen, BracketOpen, BraceOpen, Func,
+              New, Struct, If, While, For
             ];
           },
           ParOpen => {
             allowed_identifier = true;
             allowed_literal = true;
             allowed_operators = vec![
-              ParClose, ParOpen,
-              Let, If, While
+              ParClose, ParOpen, BracketOpen, BraceOpen, Minus, Bang, Func,
+              Let, If, While, For
+            ];
+          },
+          BracketOpen => {
+            allowed_identifier = true;
+            allowed_literal = true;
+            allowed_operators = vec![
+              BracketClose, BracketOpen, BraceOpen, ParOpen, Minus, Bang, Func,
+              If, While, For
             ];
           },
           ParClose => {
             allowed_operators = vec![
-              ParClose, ParOpen, Plus, Minus, Asterix, Slash, Dot, SemiColon, BraceOpen, BraceClose,
-              EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, EOF
+              ParClose, ParOpen, Plus, Minus, Asterix, DoubleAsterix, Slash, TildeSlash, Percent, Dot, DotDot, Comma, SemiColon, BraceOpen, BraceClose,
+              Ampersand, Pipe, Caret, DoubleLt, DoubleGt,
+              BracketOpen, BracketClose, Colon,
+              EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, And, Or, EOF
+            ];
+          },
+          BracketClose => {
+            allowed_operators = vec![
+              BracketClose, BracketOpen, ParClose, ParOpen, Equals, PlusEquals, MinusEquals, AsterixEquals, SlashEquals, Plus, Minus, Asterix, DoubleAsterix, Slash, TildeSlash, Percent, Dot, Comma, SemiColon,
+              Ampersand, Pipe, Caret, DoubleLt, DoubleGt,
+              BraceOpen, BraceClose,
+              EqualsEquals, BangEquals, LtOrEquals, GtOrEquals, Gt, Lt, And, Or, EOF
             ];
           },
           Func => {
@@ -145,15 +203,18 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
           },
           Struct => {
             allowed_operators = vec![
-              BraceOpen
+              BraceOpen, Extends
             ];
           },
+          Extends => {
+            allowed_identifier = true;
+          },
           SemiColon => {
             allowed_identifier = true;
             allowed_literal = true;
             allowed_operators = vec![
-              ParOpen, EOF, BraceClose,
-              Let, If, While,
+              ParOpen, BracketOpen, EOF, BraceClose, Func,
+              Let, If, While, For, Return, Break, Continue,
             ];
           },
           Let => {
@@ -184,20 +245,51 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
               Minus, Bang, ParOpen
             ];
           },
+          For => {
+            allowed_identifier = true;
+          },
+          In => {
+            allowed_identifier = true;
+            allowed_literal = true;
+            allowed_operators = vec![
+              Minus, ParOpen, BracketOpen, BraceOpen, New
+            ];
+          },
+          DotDot => {
+            allowed_identifier = true;
+            allowed_literal = true;
+            allowed_operators = vec![
+              Minus, ParOpen
+            ];
+          },
           BraceOpen => {
             allowed_identifier = true;
             allowed_literal = true;
             allowed_operators = vec![
-              Minus, Bang, ParOpen, BraceClose, Func,
-              Let, If, While
+              Minus, Bang, ParOpen, BracketOpen, BraceClose, Func,
+              Let, If, While, For, Return, Break, Continue
+            ];
+          },
+          Return | Break => {
+            allowed_identifier = true;
+            allowed_literal = true;
+            allowed_operators = vec![
+              Minus, Bang, ParOpen, BracketOpen, BraceOpen, SemiColon, BraceClose,
+              New, Struct, If, While, For, Func, EOF
+            ];
+          },
+          Continue => {
+            allowed_operators = vec![
+              SemiColon, BraceClose, EOF
             ];
           },
           BraceClose => {
             // allowed_identifier = true;
             // allowed_literal = true;
             allowed_operators = vec![
-              Else, SemiColon, BraceClose,
-              ParClose, Plus, Minus, Asterix, Slash, EOF
+              Else, SemiColon, BraceOpen, BraceClose, BracketOpen, BracketClose, Comma,
+              ParClose, Plus, Minus, Asterix, DoubleAsterix, Slash, TildeSlash, Percent, EqualsEquals, BangEquals, And, Or, EOF,
+              Ampersand, Pipe, Caret, DoubleLt, DoubleGt
             ];
           }
           _ => {
@@ -210,10 +302,19 @@ pub fn check(lexed: &Vec<Lexed>) -> Result<(), ParserErr> {
     i += 1;
   }
 
-  Ok(())
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(errors)
+  }
+}
+
+fn is_semicolon(lexed: &Lexed) -> bool {
+  matches!(*lexed, Lexed::Operator(SemiColon, _))
 }
 
-pub fn parse(lexed: Vec<Lexed>) -> Result<Vec<Box<Declaration>>, ParserErr> {
+/// Parses the whole program, with the errors of all statements that failed.
+pub fn parse(lexed: Vec<Lexed>) -> Result<Vec<Box<Declaration>>, Vec<ParserErr>> {
   check(&lexed)?;
   
   let mut g = grammar::Grammar::new(lexed);
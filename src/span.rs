@@ -0,0 +1,83 @@
+//! Positions in the source code, produced by the lexer and carried through the parser,
+//! the builder and the debug table of the bytecode.
+
+/// A range of source code.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+  pub file: u32, // index of the source it's in, 0 until the program is loaded into a session
+  pub start: u32, // char offset
+  pub end: u32, // exclusive
+  pub line: u32, // 1-indexed, 0 if unknown
+  pub col: u32 // 1-indexed
+}
+
+impl Span {
+  pub fn width(&self) -> u32 {
+    if self.end > self.start { self.end - self.start } else { 1 }
+  }
+
+  /// From the start of this span to the end of `other`.
+  pub fn to(&self, other: Span) -> Span {
+    Span {
+      end: other.end.max(self.end),
+      ..*self
+    }
+  }
+}
+
+/// Named source code, a file or a line entered in the shell.
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+  pub name: String,
+  pub code: String
+}
+
+impl Source {
+  pub fn new(name: &str, code: &str) -> Self {
+    Self {
+      name: name.to_string(),
+      code: code.to_string()
+    }
+  }
+
+  /// The text of a 1-indexed line.
+  pub fn line(&self, line: u32) -> Option<&str> {
+    if line == 0 {
+      return None;
+    }
+    self.code.split('\n').nth(line as usize - 1)
+  }
+}
+
+/// Turns char offsets into spans with their line and column.
+pub struct LineMap {
+  line_starts: Vec<u32>
+}
+
+impl LineMap {
+  pub fn new(code: &str) -> Self {
+    let mut line_starts = vec![0];
+    for (k, c) in code.chars().enumerate() {
+      if c == '\n' {
+        line_starts.push(k as u32 + 1);
+      }
+    }
+    Self { line_starts }
+  }
+
+  pub fn span(&self, start: i32, end: i32) -> Span {
+    let start = start.max(0) as u32;
+    let line = match self.line_starts.binary_search(&start) {
+      Ok(k) => k + 1,
+      Err(k) => k
+    };
+
+    Span {
+      file: 0,
+      start,
+      end: (end.max(0) as u32).max(start),
+      line: line as u32,
+      col: start - self.line_starts[line - 1] + 1
+    }
+  }
+}
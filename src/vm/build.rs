@@ -1,43 +1,87 @@
+use std::{error, fmt};
+
 use vm::*;
 
 use lexer::Literal;
+use vm::format::Constant;
+use span::{Span, Source};
 
 pub use parser::{Declaration, Statement, Expression, Primary};
 
 #[derive(Debug)]
 pub enum VMBuildError {
   // error, pos
-  InvalidExpression(String, i32),
-  UnsupportedOperator(Token, i32),
-  UnsupportedType(Literal, i32),
+  InvalidExpression(String, Span),
+  UnsupportedOperator(Token, Span),
+  UnsupportedType(Literal, Span),
+  ReturnOutsideFunction(Span),
+  BreakOutsideLoop(Span),
+  ContinueOutsideLoop(Span),
 
   Temp
 }
 
-fn get_string_binary(string: &str) -> Vec<u8> {
-  let mut string: Vec<u8> = string.as_bytes().to_vec();
-  string.push(u(NULL));
-  string
+impl VMBuildError {
+  pub fn span(&self) -> Option<Span> {
+    match *self {
+      VMBuildError::InvalidExpression(_, span) |
+      VMBuildError::UnsupportedOperator(_, span) |
+      VMBuildError::UnsupportedType(_, span) |
+      VMBuildError::ReturnOutsideFunction(span) |
+      VMBuildError::BreakOutsideLoop(span) |
+      VMBuildError::ContinueOutsideLoop(span) => Some(span),
+      VMBuildError::Temp => None
+    }
+  }
 }
 
-fn get_num_binary(num: f64) -> Vec<u8> {
-  let bv: [u8; 8] = unsafe {
-    mem::transmute(num)
-  };
-  bv.to_vec()
+impl fmt::Display for VMBuildError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      VMBuildError::InvalidExpression(ref desc, _) => write!(f, "invalid expression, {}", desc),
+      VMBuildError::UnsupportedOperator(ref token, _) => write!(f, "unsupported operator: {:?}", token),
+      VMBuildError::UnsupportedType(ref literal, _) => write!(f, "unsupported type: {:?}", literal),
+      VMBuildError::ReturnOutsideFunction(_) => write!(f, "return outside of function"),
+      VMBuildError::BreakOutsideLoop(_) => write!(f, "break outside of loop"),
+      VMBuildError::ContinueOutsideLoop(_) => write!(f, "continue outside of loop"),
+      VMBuildError::Temp => write!(f, "{:?}", self)
+    }
+  }
 }
 
+impl error::Error for VMBuildError {}
+
+// operands are little-endian, regardless of the machine building or running the program
 fn get_int_binary(int: i32) -> Vec<u8> {
-  let bv: [u8; 4] = unsafe {
-    mem::transmute(int)
-  };
-  bv.to_vec()
+  int.to_le_bytes().to_vec()
+}
+
+// scope and stack depth at the start of the innermost loop body
+#[derive(Clone, Copy)]
+struct LoopContext {
+  scope_depth: usize,
+  stack_depth: usize,
+
+  // values the loop itself keeps on the stack below the body (the iterator of a for loop), dropped on break
+  kept: usize
 }
 
 pub struct VMBuild {
   is_debug: bool,
-  debug_offset: usize,
-  curr_pos: i32
+  span_offset: usize,
+  curr_pos: i32,
+  in_function: bool,
+
+  // scopes opened and temporary values on the stack at the current point of the built code,
+  // used to unwind when breaking out of a loop
+  scope_depth: usize,
+  stack_depth: usize,
+  loop_context: Option<LoopContext>,
+
+  // deduplicated, written to the module and referred to by index
+  constants: Vec<Constant>,
+  symbols: Vec<String>,
+  spans: Vec<Span>
 }
 
 impl VMBuild {
@@ -45,33 +89,244 @@ impl VMBuild {
     Self {
       is_debug: false,
       curr_pos: 0,
-      debug_offset: 0
+      span_offset: 0,
+      in_function: false,
+      scope_depth: 0,
+      stack_depth: 0,
+      loop_context: None,
+      constants: Vec::new(),
+      symbols: Vec::new(),
+      spans: Vec::new()
     }
   }
 
   fn reset(&mut self) {
     self.is_debug = false;
     self.curr_pos = 0;
+    self.in_function = false;
+    self.scope_depth = 0;
+    self.stack_depth = 0;
+    self.loop_context = None;
+    self.constants.clear();
+    self.symbols.clear();
+    self.spans.clear();
+  }
+
+  fn get_const_binary(&mut self, constant: Constant) -> Vec<u8> {
+    let index = match self.constants.iter().position(|c| *c == constant) {
+      Some(index) => index,
+      None => {
+        self.constants.push(constant);
+        self.constants.len() - 1
+      }
+    };
+    get_int_binary(index as i32)
+  }
+
+  fn get_symbol_binary(&mut self, name: &str) -> Vec<u8> {
+    let index = match self.symbols.iter().position(|s| s == name) {
+      Some(index) => index,
+      None => {
+        self.symbols.push(name.to_string());
+        self.symbols.len() - 1
+      }
+    };
+    get_int_binary(index as i32)
+  }
+
+  // debug positions are indices into the span table, after the spans of the code already running
+  fn get_debug_binary(&mut self, span: Span) -> Vec<u8> {
+    let index = match self.spans.iter().position(|s| *s == span) {
+      Some(index) => index,
+      None => {
+        self.spans.push(span);
+        self.spans.len() - 1
+      }
+    };
+    get_int_binary((index + self.span_offset) as i32)
   }
 
-  fn get_debug_binary(&self, pos: i32) -> Vec<u8> {
-    get_int_binary(pos + self.debug_offset as i32)
+  /// Short-circuiting `&&` / `||`, always results in a bool.
+  /// The right side is only evaluated if the left side doesn't decide the result.
+  ///
+  /// and: `left JUMPIFN(false) right JUMPIFN(false) PUSH_BOOL 1 JUMP(end) false: PUSH_BOOL 0 end:`
+  /// or: same as and, but with both operands negated and the pushed bools swapped
+  fn build_logical(&mut self, left: &Expression, right: &Expression, is_or: bool, pos: Span) -> Result<Vec<u8>, VMBuildError> {
+    let left_pos = match *left {
+      Expression::Primary(_, pos) => pos,
+      _ => pos
+    };
+    let right_pos = match *right {
+      Expression::Primary(_, pos) => pos,
+      _ => pos
+    };
+
+    let mut debug_info = vec![];
+    if self.is_debug {
+      debug_info.push(u(I32));
+      debug_info.append(&mut self.get_debug_binary(pos));
+    }
+
+    let mut left = self.build_binary(left, left_pos)?;
+    let mut right = self.build_binary(right, right_pos)?;
+
+    if is_or {
+      left.push(u(NOT));
+      left.append(&mut debug_info.clone());
+      right.push(u(NOT));
+      right.append(&mut debug_info.clone());
+    }
+
+    let jumpifn_len = 1 + debug_info.len() as i32 + 5;
+
+    let mut v = left;
+    v.push(u(JUMPIFN));
+    v.append(&mut debug_info.clone());
+    v.push(u(I32));
+    v.append(&mut get_int_binary(right.len() as i32 + jumpifn_len + 2 + 6));
+
+    v.append(&mut right);
+    v.push(u(JUMPIFN));
+    v.append(&mut debug_info);
+    v.push(u(I32));
+    v.append(&mut get_int_binary(2 + 6));
+
+    v.push(u(PUSH_BOOL));
+    v.push(if is_or {0x00} else {0x01});
+    v.push(u(JUMP));
+    v.push(u(I32));
+    v.append(&mut get_int_binary(2));
+
+    v.push(u(PUSH_BOOL));
+    v.push(if is_or {0x01} else {0x00});
+
+    Ok(v)
   }
 
-  fn build_binary(&mut self, expr: &Expression, pos: i32) -> Result<Vec<u8>, VMBuildError> {
+  // `a += b` evaluates the target once and updates it through ASSIGN or SET_INDEX, like `a = a + b`
+  fn build_compound(&mut self, target: &Expression, operator: Token, value: &Expression, pos: Span) -> Result<Vec<u8>, VMBuildError> {
+    let op_code = match operator {
+      Token::PlusEquals => ADD,
+      Token::MinusEquals => SUB,
+      Token::AsterixEquals => MULTIPLY,
+      Token::SlashEquals => DIVIDE,
+      _ => return Err(VMBuildError::UnsupportedOperator(operator, pos))
+    };
+
+    let mut debug_info = vec![];
+    if self.is_debug {
+      debug_info.push(u(I32));
+      debug_info.append(&mut self.get_debug_binary(pos));
+    }
+
+    let mut v = vec![];
+    match *target {
+      Expression::Primary(Primary::Identifier(_), target_pos) | Expression::DotExpr(_, _, target_pos) => {
+        // the variable, or the pointer into the instance, twice
+        v.append(&mut self.build_binary(target, target_pos)?);
+        v.push(u(DUP));
+        v.push(u(I32));
+        v.append(&mut get_int_binary(1));
+
+        self.stack_depth += 2;
+        v.append(&mut self.build_expr(value, pos)?);
+        self.stack_depth -= 2;
+
+        v.push(u(op_code));
+        v.append(&mut debug_info.clone());
+        v.push(u(ASSIGN));
+        v.append(&mut debug_info);
+      },
+      Expression::IndexExpr(ref collection, ref index, index_pos) => {
+        // the collection and the index twice, once to get the element and once to set it
+        v.append(&mut self.build_expr(collection, pos)?);
+        self.stack_depth += 1;
+        v.append(&mut self.build_expr(index, pos)?);
+        self.stack_depth += 1;
+        v.push(u(DUP));
+        v.push(u(I32));
+        v.append(&mut get_int_binary(2));
+
+        v.push(u(GET_INDEX));
+        if self.is_debug {
+          v.push(u(I32));
+          v.append(&mut self.get_debug_binary(index_pos));
+        }
+
+        self.stack_depth += 1;
+        v.append(&mut self.build_expr(value, pos)?);
+        self.stack_depth -= 3;
+
+        v.push(u(op_code));
+        v.append(&mut debug_info.clone());
+        v.push(u(SET_INDEX));
+        v.append(&mut debug_info);
+      },
+      _ => return Err(VMBuildError::InvalidExpression(String::from("can only assign to a variable, field or index"), pos))
+    }
+
+    Ok(v)
+  }
+
+  fn build_binary(&mut self, expr: &Expression, pos: Span) -> Result<Vec<u8>, VMBuildError> {
     let v = match expr {
+      &Expression::Binary(_, (Token::Bang, pos), ref right) => { // unary, left is a placeholder
+        let right_pos = match **right {
+          Expression::Primary(_, pos) => pos,
+          _ => pos
+        };
+
+        let mut v = self.build_binary(&*right, right_pos)?;
+        v.push(u(NOT));
+
+        if self.is_debug {
+          v.push(u(I32));
+          v.append(&mut self.get_debug_binary(pos));
+        }
+
+        v
+      },
+      &Expression::Binary(ref left, (Token::Equals, pos), ref right) if match **left { Expression::IndexExpr(..) => true, _ => false } => {
+        let (collection, index) = match **left {
+          Expression::IndexExpr(ref collection, ref index, _) => (collection, index),
+          _ => unreachable!()
+        };
+
+        let mut v = self.build_expr(collection, pos)?;
+        self.stack_depth += 1;
+        v.append(&mut self.build_expr(index, pos)?);
+        self.stack_depth += 1;
+        v.append(&mut self.build_expr(right, pos)?);
+        self.stack_depth -= 2;
+
+        v.push(u(SET_INDEX));
+        if self.is_debug {
+          v.push(u(I32));
+          v.append(&mut self.get_debug_binary(pos));
+        }
+
+        v
+      },
+      &Expression::Binary(ref left, (operator @ Token::PlusEquals, pos), ref right) |
+      &Expression::Binary(ref left, (operator @ Token::MinusEquals, pos), ref right) |
+      &Expression::Binary(ref left, (operator @ Token::AsterixEquals, pos), ref right) |
+      &Expression::Binary(ref left, (operator @ Token::SlashEquals, pos), ref right) => self.build_compound(left, operator, right, pos)?,
+      &Expression::Binary(ref left, (Token::And, pos), ref right) => self.build_logical(left, right, false, pos)?,
+      &Expression::Binary(ref left, (Token::Or, pos), ref right) => self.build_logical(left, right, true, pos)?,
       &Expression::Binary(ref left, ref token, ref right) => {
         let left_pos = match **left {
           Expression::Primary(_, pos) => pos,
-          _ => 0
+          _ => token.1
         };
         let right_pos = match **right {
           Expression::Primary(_, pos) => pos,
-          _ => 0
+          _ => token.1
         };
 
         let mut left = self.build_binary(&*left, left_pos)?;
+        self.stack_depth += 1;
         let mut right = self.build_binary(&*right, right_pos)?;
+        self.stack_depth -= 1;
 
         left.append(&mut right);
 
@@ -82,11 +337,22 @@ impl VMBuild {
           &(Token::Equals, _) => ASSIGN,
           &(Token::Let, _) => LET,
           &(Token::Slash, _) => DIVIDE,
+          &(Token::TildeSlash, _) => INT_DIVIDE,
+          &(Token::Percent, _) => MOD,
+          &(Token::DoubleAsterix, _) => POW,
+          &(Token::Ampersand, _) => BIT_AND,
+          &(Token::Pipe, _) => BIT_OR,
+          &(Token::Caret, _) => BIT_XOR,
+          &(Token::DoubleLt, _) => SHL,
+          &(Token::DoubleGt, _) => SHR,
           
           &(Token::Lt, _) => LT,
           &(Token::Gt, _) => GT,
           &(Token::LtOrEquals, _) => LTOREQ,
           &(Token::GtOrEquals, _) => GTOREQ,
+          &(Token::EqualsEquals, _) => EQ,
+          &(Token::BangEquals, _) => NEQ,
+          &(Token::DotDot, _) => RANGE,
 
           &(_, pos) => return Err(VMBuildError::UnsupportedOperator(token.0, pos))
         }));
@@ -111,10 +377,7 @@ impl VMBuild {
             // v.push(u(NULL));
             let mut v = vec![];
             v.push(u(PUSH_VAR));
-
-            let mut s: Vec<u8> = identifier.clone().into_bytes();
-            v.append(&mut s);
-            v.push(u(NULL));
+            v.append(&mut self.get_symbol_binary(identifier));
 
             if self.is_debug {
               v.push(u(I32));
@@ -126,13 +389,13 @@ impl VMBuild {
           &Primary::Literal(ref literal) => {
             match literal {
               &lexer::Literal::Num(num) => {
-                let bv: [u8; 8] = unsafe {
-                  mem::transmute(num)
-                };
-                let mut bv: Vec<u8> = bv.to_vec();
-
-                let mut v = vec![u(PUSH_NUM)];
-                v.append(&mut bv);
+                let mut v = vec![u(PUSH_CONST)];
+                v.append(&mut self.get_const_binary(Constant::Num(num)));
+                v
+              },
+              &lexer::Literal::Int(int) => {
+                let mut v = vec![u(PUSH_CONST)];
+                v.append(&mut self.get_const_binary(Constant::Int(int)));
                 v
               },
               &lexer::Literal::Bool(b) => {
@@ -140,8 +403,8 @@ impl VMBuild {
                 v
               },
               &lexer::Literal::String(ref s) => {
-                let mut v = vec![u(PUSH_STRING)];
-                v.append(&mut get_string_binary(s));
+                let mut v = vec![u(PUSH_CONST)];
+                v.append(&mut self.get_const_binary(Constant::String(s.clone())));
                 v
               },
               &lexer::Literal::Nil => {
@@ -153,6 +416,8 @@ impl VMBuild {
         }
       },
       &Expression::IfExpr(ref expr, ref body, ref else_body, expr_pos, pos) => {
+        self.scope_depth += 1;
+
         let mut body_v = Vec::new();
         let mut body_len = 0;
         for i in body {
@@ -199,6 +464,8 @@ impl VMBuild {
           }
         }
 
+        self.scope_depth -= 1;
+
         let mut debug_info = vec![];
         if self.is_debug {
           debug_info.push(u(I32));
@@ -231,101 +498,216 @@ impl VMBuild {
         v
       },
       &Expression::WhileExpr(ref expr, ref body, expr_pos, pos) => {
+        // PUSH_NIL                   result if the body never runs
+        // PUSH_LOOP end              jump_stack: [continue: cond, break: end]
+        // cond: expr
+        // JUMPIFN exit
+        // POP                        result of the previous iteration
+        // SCOPE_NEW body PUSH_VALUE SCOPE_END
+        // JUMP cond
+        // exit: POP_LOOP
+        // end:
+
+        let loop_context = self.loop_context;
+
+        self.loop_context = None; // break in the condition would leave the result on the stack
+        self.stack_depth += 1;
+        let mut expr = self.build_binary(&*expr, expr_pos)?;
+        self.stack_depth -= 1;
+
+        self.loop_context = Some(LoopContext {
+          scope_depth: self.scope_depth,
+          stack_depth: self.stack_depth,
+          kept: 0
+        });
+        self.scope_depth += 1;
+
         let mut body_v = Vec::new();
-        let mut body_len: i32 = 0;
         for i in body {
-          let decl = self.build_decl(i)?;
-          body_len += decl.len() as i32;
-          body_v.push(decl);
+          body_v.append(&mut self.build_decl(i)?);
         }
 
-        let mut debug_offset = 0;
-        let mut debug_info = vec![];
-        if self.is_debug {
-          debug_info.push(u(I32));
-          debug_info.append(&mut self.get_debug_binary(expr_pos));
-          debug_offset = 5;
-        }
+        self.scope_depth -= 1;
+        self.loop_context = loop_context;
 
-        let last_is_expr: bool = body_len <= 0 || match *(*body)[body.len() - 1] {
+        let last_is_stmt: bool = body.len() <= 0 || match *(*body)[body.len() - 1] {
           Declaration::Statement(ref stmt, _) => match **stmt {
-            Statement::ExpressionStmt(_, is_expr, _) => is_expr,
-            _ => false // should not happen
-          },
-          _ => false // should not happen
+            Statement::ExpressionStmt(_, is_stmt, _) => is_stmt,
+            _ => false
+          }
         };
 
-        if last_is_expr {
-          body_v.push(vec![u(PUSH_NIL)]);
-          body_len += 1;
+        if last_is_stmt {
+          body_v.push(u(PUSH_NIL));
+        }
+
+        let mut debug_info = vec![];
+        if self.is_debug {
+          debug_info.push(u(I32));
+          debug_info.append(&mut self.get_debug_binary(expr_pos));
         }
 
-        let mut expr = self.build_binary(&*expr, expr_pos)?;
         let expr_len = expr.len() as i32;
+        let jumpifn_len = 1 + debug_info.len() as i32 + 5;
+        let body_len = 1 + 1 + body_v.len() as i32 + 1 + 1; // POP SCOPE_NEW body PUSH_VALUE SCOPE_END
 
         let mut v: Vec<u8> = Vec::new();
 
-        v.push(u(SCOPE_NEW));
-        v.push(u(JUMP));                // 1. Jump
-        v.push(u(I32));
-        v.append(&mut get_int_binary(expr_len + 1 + 5 + 6 + 11 + 1 + 6 - 5 + 2 + debug_offset));
+        v.push(u(PUSH_NIL));
+
+        v.push(u(PUSH_LOOP));
+        v.append(&mut get_int_binary(expr_len + jumpifn_len + body_len + 6 + 1));
 
-        v.push(u(SCOPE_BACK));          // 2. Expr
         v.append(&mut expr);
-        v.push(u(SCOPE_FORWARD));
 
-        v.push(u(JUMPSTACK));           // 3. JumpStack
+        v.push(u(JUMPIFN));
+        v.append(&mut debug_info);
+        v.push(u(I32));
+        v.append(&mut get_int_binary(body_len + 6));
 
-        v.push(u(PUSH_JUMP));            // 4. PushInt
-        v.append(&mut get_int_binary(5 + 1 + 5));
+        v.push(u(POP));
+        v.push(u(SCOPE_NEW));
+        v.append(&mut body_v);
+        v.push(u(PUSH_VALUE));
+        v.push(u(SCOPE_END));
 
-        v.push(u(JUMP));                // 5. Jump
+        v.push(u(JUMP));
         v.push(u(I32));
-        v.append(&mut get_int_binary(-(5 + 1 + 1 + expr_len + 1 + 5 + 1)));
+        v.append(&mut get_int_binary(-(expr_len + jumpifn_len + body_len + 6)));
 
-        v.push(u(JUMPIFN));             // 6. JumpIfNot
-        v.append(&mut debug_info.clone());
-        v.push(u(I32));
-        v.append(&mut get_int_binary(1 + 6 + 5 + 6 + 6 + debug_offset + body_len + 6));
+        v.push(u(POP_LOOP));
 
-        v.push(u(POP));                 // 7. Pop
+        v
+      },
+      &Expression::ForExpr(ref identifier, ref expr, ref body, expr_pos, _pos) => {
+        // expr
+        // ITER skip_iter                     iterator, or a struct's __iter__ to call
+        // CALL_FUNC 0 SCOPE_END ITER_RESULT  calls __iter__
+        // skip_iter: PUSH_NIL                result if the body never runs
+        // PUSH_LOOP end                      jump_stack: [continue: cond, break: end]
+        // cond: ITER_NEXT skip_next exit     next value, or a struct's __next__ to call
+        // CALL_FUNC 0 SCOPE_END              calls __next__
+        // ITER_CHECK exit                    nil from __next__ ends the loop
+        // skip_next: SCOPE_NEW DECLARE identifier
+        // POP                                result of the previous iteration
+        // body PUSH_VALUE SCOPE_END
+        // JUMP cond
+        // exit: BREAK 1                      drops the iterator, keeps the result
+        // end:
+
+        let loop_context = self.loop_context;
+
+        self.loop_context = None;
+        let mut expr = self.build_binary(&*expr, expr_pos)?;
 
-        v.push(u(JUMP));                // 8. Jump
-        v.push(u(I32));
-        v.append(&mut get_int_binary(5 + 6 + 6 + debug_offset));
+        self.stack_depth += 1; // iterator
+        self.loop_context = Some(LoopContext {
+          scope_depth: self.scope_depth,
+          stack_depth: self.stack_depth,
+          kept: 1
+        });
+        self.scope_depth += 1;
 
-        v.push(u(PUSH_JUMP));            // 9. PushInt
-        v.append(&mut get_int_binary(5 + 6 + 11 + 1 + 6 + 5 + 6 - 3 - 2 + debug_offset));
+        let mut body_v = Vec::new();
+        for i in body {
+          body_v.append(&mut self.build_decl(i)?);
+        }
+
+        self.scope_depth -= 1;
+        self.stack_depth -= 1;
+        self.loop_context = loop_context;
 
-        v.push(u(JUMP));                // 10. Jump
+        let last_is_stmt: bool = body.len() <= 0 || match *(*body)[body.len() - 1] {
+          Declaration::Statement(ref stmt, _) => match **stmt {
+            Statement::ExpressionStmt(_, is_stmt, _) => is_stmt,
+            _ => false
+          }
+        };
+
+        if last_is_stmt {
+          body_v.push(u(PUSH_NIL));
+        }
+
+        let mut debug_info = vec![];
+        if self.is_debug {
+          debug_info.push(u(I32));
+          debug_info.append(&mut self.get_debug_binary(expr_pos));
+        }
+
+        let mut call = vec![u(CALL_FUNC)];
+        call.append(&mut debug_info.clone());
+        call.push(u(I32));
+        call.append(&mut get_int_binary(0));
+        call.push(u(SCOPE_END));
+
+        let mut iter_call = call.clone();
+        iter_call.push(u(ITER_RESULT));
+        iter_call.append(&mut debug_info.clone());
+
+        let mut next_call = call;
+        next_call.push(u(ITER_CHECK));
+        next_call.append(&mut debug_info.clone());
+        next_call.push(u(I32));
+        let next_call_len = next_call.len() as i32 + 4;
+
+        let mut declare = vec![u(SCOPE_NEW), u(DECLARE), u(STRING)];
+        declare.append(&mut self.get_symbol_binary(identifier));
+        declare.push(u(POP));
+
+        let iter_next_len = 1 + debug_info.len() as i32 + 5 + 5;
+        let body_len = declare.len() as i32 + body_v.len() as i32 + 1 + 1; // PUSH_VALUE SCOPE_END
+        let exit_len = 6;
+
+        let mut v = expr;
+
+        v.push(u(ITER));
+        v.append(&mut debug_info.clone());
         v.push(u(I32));
-        v.append(&mut get_int_binary(-(5 + 6 + 1 + 11 + 6 + 5 + 1 + expr_len + 1 + 2 + debug_offset)));
+        v.append(&mut get_int_binary(iter_call.len() as i32));
+        v.append(&mut iter_call);
 
-        v.push(u(JUMPIFN));             // 11. JumpIfNot
+        v.push(u(PUSH_NIL));
+
+        v.push(u(PUSH_LOOP));
+        v.append(&mut get_int_binary(iter_next_len + next_call_len + body_len + 6 + exit_len));
+
+        v.push(u(ITER_NEXT));
         v.append(&mut debug_info);
         v.push(u(I32));
+        v.append(&mut get_int_binary(next_call_len));
+        v.push(u(I32));
+        v.append(&mut get_int_binary(next_call_len + body_len + 6));
+
+        v.append(&mut next_call);
         v.append(&mut get_int_binary(body_len + 6));
 
-        for mut i in body_v {           // 12. Body
-          v.append(&mut i);
-        }
+        v.append(&mut declare);
+        v.append(&mut body_v);
+        v.push(u(PUSH_VALUE));
+        v.push(u(SCOPE_END));
 
-        v.push(u(JUMP));                // 13. Jump
+        v.push(u(JUMP));
         v.push(u(I32));
-        v.append(&mut get_int_binary(-(body_len + 5 + debug_offset + 1 + 5 + 1 + 5 + 5 + 1 + 1 + 5 + debug_offset + 1 + 5 + 1 + 5 + 1 + 5)));
+        v.append(&mut get_int_binary(-(iter_next_len + next_call_len + body_len + 6)));
 
-        v.push(u(SCOPE_END));
+        v.push(u(BREAK));
+        v.push(u(I32));
+        v.append(&mut get_int_binary(1));
 
         v
-        // println!("{:?}", get_program(v));
-        // Ok(vec![u(PUSH_NIL)])
       },
-      &Expression::StructExpr(ref body, pos) => {
+      &Expression::StructExpr(ref body, ref base, pos) => {
         let mut body_v = Vec::new();
 
+        let in_function = self.in_function;
+        let loop_context = self.loop_context;
+        self.in_function = false;
+        self.loop_context = None;
         for i in body {
           body_v.append(&mut self.build_decl(i)?);
         }
+        self.in_function = in_function;
+        self.loop_context = loop_context;
 
         let mut debug_info = Vec::new();
         if self.is_debug {
@@ -345,7 +727,31 @@ impl VMBuild {
           body_v.push(u(POP));
         }
 
-        let mut init_func = String::from("__init__").into_bytes();
+        // PUSH_NIL base CALL_STRUCT EXTEND, the nil argument length builds the base without calling its __init__
+        let mut extend_v = Vec::new();
+        if let Some(ref base) = *base {
+          extend_v.push(u(PUSH_NIL));
+          self.stack_depth += 1;
+          extend_v.append(&mut self.build_binary(base, pos)?);
+          self.stack_depth -= 1;
+
+          extend_v.push(u(CALL_STRUCT));
+          extend_v.append(&mut debug_info.clone());
+          extend_v.push(u(EXTEND));
+          extend_v.append(&mut debug_info.clone());
+        }
+
+        let mut init_v = vec![u(PUSH_VAR)];
+        init_v.append(&mut self.get_symbol_binary("__init__"));
+        init_v.append(&mut debug_info.clone());
+
+        init_v.push(u(CALL_FUNC_STACK_ARGS));
+        init_v.append(&mut debug_info.clone());
+
+        init_v.push(u(POP));
+
+        init_v.push(u(SCOPE_PUSH));
+        init_v.push(u(JUMPSTACKABS));
 
         let mut v = vec![u(PUSH_STRUCT)];
         v.append(&mut debug_info);
@@ -355,25 +761,12 @@ impl VMBuild {
 
         v.push(u(JUMP));
         v.push(u(I32));
-        v.append(&mut get_int_binary(1 + body_v.len() as i32 + 1 + init_func.len() as i32 + 1 + 1 + 4 + 1 + 1 + 4 + 1 + 1 + 1));
+        v.append(&mut get_int_binary(1 + extend_v.len() as i32 + body_v.len() as i32 + init_v.len() as i32));
 
         v.push(u(SCOPE_NEW));
+        v.append(&mut extend_v);
         v.append(&mut body_v);
-
-        v.push(u(PUSH_VAR));
-        v.append(&mut init_func);
-        v.push(u(NULL));
-        v.push(u(I32));
-        v.append(&mut get_int_binary(-1));
-
-        v.push(u(CALL_FUNC_STACK_ARGS));
-        v.push(u(I32));
-        v.append(&mut get_int_binary(-1));
-
-        v.push(u(POP));
-
-        v.push(u(SCOPE_PUSH));
-        v.push(u(JUMPSTACKABS));
+        v.append(&mut init_v);
 
         v
         // vec![u(PUSH_NIL)]
@@ -385,18 +778,18 @@ impl VMBuild {
           debug_info.append(&mut self.get_debug_binary(pos));
         }
 
-        let mut expr = self.build_binary(expr, pos)?;
-
         let mut v = Vec::new();
         for i in args {
           v.append(&mut self.build_expr(i, pos)?);
-          // v.push(u(POP)); // temp
+          self.stack_depth += 1;
         }
 
-        v.push(u(PUSH_INT));
-        v.append(&mut get_int_binary(args.len() as i32));
+        v.push(u(PUSH_CONST));
+        v.append(&mut self.get_const_binary(Constant::Int(args.len() as i64)));
 
-        v.append(&mut expr);
+        self.stack_depth += 1;
+        v.append(&mut self.build_binary(expr, pos)?);
+        self.stack_depth -= args.len() + 1;
 
         v.push(u(CALL_STRUCT));
         v.append(&mut debug_info);
@@ -419,7 +812,7 @@ impl VMBuild {
         // v.push(u(PUSH_VALUE_DIRECT));
         v.push(u(PUSH_POINTER));
         v.push(u(STRING));
-        v.append(&mut get_string_binary(lookup));
+        v.append(&mut self.get_symbol_binary(lookup));
         v.append(&mut debug_info);
 
         v.push(u(SCOPE_BACK));
@@ -428,20 +821,27 @@ impl VMBuild {
       },
       &Expression::FunctionExpr(ref parameters, ref body, pos) => {
         let mut body_v = Vec::new();
+
+        let in_function = self.in_function;
+        let loop_context = self.loop_context;
+        self.in_function = true;
+        self.loop_context = None;
         for i in body {
           body_v.append(&mut self.build_decl(i)?);
         }
+        self.in_function = in_function;
+        self.loop_context = loop_context;
 
         let mut parameter_v = Vec::new();
         for i in parameters {
           parameter_v.push(u(STRING));
-          parameter_v.append(&mut get_string_binary(i));
+          parameter_v.append(&mut self.get_symbol_binary(i));
         }
 
         let last_is_stmt = body.len() <= 0 || match &*body[body.len() - 1] {
           &Declaration::Statement(ref stmt, _) => match &**stmt {
             &Statement::ExpressionStmt(ref _box, is_stmt, _) => is_stmt,
-            // _ => false
+            _ => true
           },
           // _ => false
         };
@@ -450,15 +850,18 @@ impl VMBuild {
           body_v.push(u(PUSH_NIL));
         }
 
-        let mut v = vec![u(PUSH_FUNC)];
-
-        // let mut debug_offset = 0;
+        let mut debug_info = Vec::new();
         if self.is_debug {
-          v.push(u(I32));
-          v.append(&mut self.get_debug_binary(pos));
-          // debug_offset = 5;
+          debug_info.push(u(I32));
+          debug_info.append(&mut self.get_debug_binary(pos));
         }
 
+        body_v.push(u(RETURN));
+        body_v.append(&mut debug_info.clone());
+
+        let mut v = vec![u(PUSH_FUNC)];
+        v.append(&mut debug_info);
+
         v.push(u(I32));
         v.append(&mut get_int_binary(1 + 1 + 4));
 
@@ -469,12 +872,9 @@ impl VMBuild {
 
         v.push(u(JUMP));
         v.push(u(I32));
-        v.append(&mut get_int_binary(body_v.len() as i32 + 2));
+        v.append(&mut get_int_binary(body_v.len() as i32));
 
         v.append(&mut body_v);
-
-        v.push(u(PUSH_VALUE));
-        v.push(u(JUMPSTACKABS));
         v
       },
       &Expression::FunctionCallExpr(ref expr, ref args, pos) => {
@@ -487,12 +887,12 @@ impl VMBuild {
         let mut v = Vec::new();
 
         for i in args {
-          v.append(&mut self.build_expr(i, pos)?);
+          v.append(&mut self.build_expr(i, i.span())?);
+          self.stack_depth += 1;
         }
 
-        v.append(&mut self.build_expr(expr, pos)?);
-
-        v.push(u(SCOPE_NEW_FUNC)); // call_func handles it
+        v.append(&mut self.build_expr(expr, expr.span())?);
+        self.stack_depth -= args.len();
 
         v.push(u(CALL_FUNC));
         v.append(&mut debug_info);
@@ -504,13 +904,69 @@ impl VMBuild {
 
         v
       },
+      &Expression::ArrayExpr(ref elements, pos) => {
+        let mut v = Vec::new();
+
+        for i in elements {
+          v.append(&mut self.build_expr(i, i.span())?);
+          self.stack_depth += 1;
+        }
+        self.stack_depth -= elements.len();
+
+        v.push(u(PUSH_ARRAY));
+        if self.is_debug {
+          v.push(u(I32));
+          v.append(&mut self.get_debug_binary(pos));
+        }
+
+        v.push(u(I32));
+        v.append(&mut get_int_binary(elements.len() as i32));
+
+        v
+      },
+      &Expression::MapExpr(ref entries, pos) => {
+        let mut v = Vec::new();
+
+        for &(ref key, ref value) in entries {
+          v.append(&mut self.build_expr(key, key.span())?);
+          self.stack_depth += 1;
+          v.append(&mut self.build_expr(value, value.span())?);
+          self.stack_depth += 1;
+        }
+        self.stack_depth -= entries.len() * 2;
+
+        v.push(u(PUSH_MAP));
+        if self.is_debug {
+          v.push(u(I32));
+          v.append(&mut self.get_debug_binary(pos));
+        }
+
+        v.push(u(I32));
+        v.append(&mut get_int_binary(entries.len() as i32));
+
+        v
+      },
+      &Expression::IndexExpr(ref collection, ref index, pos) => {
+        let mut v = self.build_expr(collection, collection.span())?;
+        self.stack_depth += 1;
+        v.append(&mut self.build_expr(index, index.span())?);
+        self.stack_depth -= 1;
+
+        v.push(u(GET_INDEX));
+        if self.is_debug {
+          v.push(u(I32));
+          v.append(&mut self.get_debug_binary(pos));
+        }
+
+        v
+      },
       _ => return Err(VMBuildError::InvalidExpression(format!("{:?}", expr), pos))
     };
 
     Ok(v)
   }
 
-  fn build_expr(&mut self, expr: &Expression, pos: i32) -> Result<Vec<u8>, VMBuildError> {
+  fn build_expr(&mut self, expr: &Expression, pos: Span) -> Result<Vec<u8>, VMBuildError> {
     let binary = self.build_binary(expr, pos);
     binary
   }
@@ -522,6 +978,69 @@ impl VMBuild {
         if is_statement {
           res.push(u(POP));
         }
+        Ok(res)
+      },
+      &Statement::Return(ref expr, pos) => {
+        if !self.in_function {
+          return Err(VMBuildError::ReturnOutsideFunction(pos));
+        }
+
+        let mut res = match expr {
+          &Some(ref expr) => self.build_expr(expr, pos)?,
+          &None => vec![u(PUSH_NIL)]
+        };
+
+        res.push(u(RETURN));
+        if self.is_debug {
+          res.push(u(I32));
+          res.append(&mut self.get_debug_binary(pos));
+        }
+
+        Ok(res)
+      },
+      &Statement::Break(ref expr, pos) => {
+        let context = match self.loop_context {
+          Some(context) => context,
+          None => return Err(VMBuildError::BreakOutsideLoop(pos))
+        };
+
+        let mut res = match expr {
+          &Some(ref expr) => self.build_expr(expr, pos)?,
+          &None => vec![u(PUSH_NIL)]
+        };
+        res.push(u(PUSH_VALUE));
+
+        for _ in context.scope_depth..self.scope_depth {
+          res.push(u(SCOPE_END));
+        }
+
+        res.push(u(BREAK));
+        res.push(u(I32));
+        res.append(&mut get_int_binary((self.stack_depth - context.stack_depth + context.kept) as i32));
+
+        Ok(res)
+      },
+      &Statement::Continue(pos) => {
+        let context = match self.loop_context {
+          Some(context) => context,
+          None => return Err(VMBuildError::ContinueOutsideLoop(pos))
+        };
+
+        let mut res = Vec::new();
+        for _ in context.stack_depth..self.stack_depth {
+          res.push(u(POP));
+        }
+        for _ in context.scope_depth..self.scope_depth {
+          res.push(u(SCOPE_END));
+        }
+
+        res.push(u(PUSH_NIL)); // result of this iteration
+        res.push(u(CONTINUE));
+        if self.is_debug {
+          res.push(u(I32));
+          res.append(&mut self.get_debug_binary(pos));
+        }
+
         Ok(res)
       }
     }
@@ -533,26 +1052,16 @@ impl VMBuild {
     }
   }
 
-  pub fn build(&mut self, decls: Decls, query: String, debug_offset: usize, options: BuildOptions) -> Result<Instructions, VMBuildError> {
+  pub fn build(&mut self, decls: Decls, source: Source, span_offset: usize, options: BuildOptions) -> Result<Instructions, VMBuildError> {
     self.reset();
-    self.debug_offset = debug_offset;
+    self.span_offset = span_offset;
 
-    let mut program: Vec<u8> = vec![u(VERSION), 0x01];
+    let mut program: Vec<u8> = Vec::new();
 
     if options.contains(BuildOptions::DEBUG) {
       self.is_debug = true;
-      program.push(u(DEBUG))
     }
 
-    if options.contains(BuildOptions::CODE) {
-      program.push(u(DEBUG_CODE));
-      let mut code = query.as_bytes().to_vec();
-      program.append(&mut code);
-      program.push(u(DEBUG_CODE_END));
-    }
-
-    program.push(u(META_END));
-
     for i in decls {
       self.curr_pos = program.len() as i32;
       let mut built = self.build_decl(&*i)?;
@@ -561,6 +1070,51 @@ impl VMBuild {
     
     program.push(u(END));
 
-    Ok(program)
+    let mut module = format::Module {
+      is_debug: self.is_debug,
+      code: program,
+      constants: self.constants.clone(),
+      symbols: self.symbols.clone(),
+      file: source.name,
+      spans: self.spans.clone(),
+      ..Default::default()
+    };
+
+    if options.contains(BuildOptions::CODE) {
+      module.lines = self.get_lines(&module);
+      module.source = Some(source.code);
+    }
+
+    Ok(format::write(&module))
+  }
+
+  /// The line table of the built code, the source line of the first instruction on every line.
+  fn get_lines(&self, module: &format::Module) -> Vec<(u32, u32)> {
+    let operations = get_operations(module);
+    let mut lines: Vec<(u32, u32)> = Vec::new();
+    let mut i = 0;
+    while i < operations.len() {
+      let instruction = match verify::read_instruction(&operations, i, self.is_debug) {
+        Ok(instruction) => instruction,
+        Err(_) => break
+      };
+
+      let span = instruction.operands.iter().filter_map(|operand| match *operand {
+        (verify::Kind::Pos, verify::Operand::Int(pos)) if pos >= self.span_offset as i32 => self.spans.get(pos as usize - self.span_offset),
+        _ => None
+      }).next();
+
+      if let Some(span) = span {
+        let line = span.line;
+
+        if lines.last().map(|&(_, last)| last) != Some(line) {
+          lines.push((instruction.start as u32, line));
+        }
+      }
+
+      i = instruction.end + 1;
+    }
+
+    lines
   }
 }
\ No newline at end of file
@@ -7,7 +7,8 @@ pub type NativeScope = *mut Scope;
 pub type NativeReturn = Result<Option<Value>, VMExecError>;
 
 pub enum FunctionType<'a> {
-  InCode(i32, &'a Vec<String>),
+  // op_i, parameters, scope it was defined in, instance of a method
+  InCode(i32, &'a Vec<String>, *mut Scope, Option<*mut Scope>),
   Native(fn(NativeScope, NativePars) -> NativeReturn),
 }
 
@@ -49,7 +50,7 @@ impl VMExec {
         }
       },
       &Value::Variable(ref identifier, pos) => self.match_var(identifier, scope, expr_pos, &Self::cast_bool)?,
-      &Value::Pointer(ref identifier, pos, scope) => self.match_var(identifier, scope, expr_pos, &Self::cast_bool)?,
+      &Value::Pointer(ref identifier, pos, scope) => self.cast_bool(unsafe { &*scope }.get_field(identifier, pos)?, expr_pos)?,
       _ => {
         return Err(VMExecError::Temp(5));
       }
@@ -67,7 +68,7 @@ impl VMExec {
       &Value::Literal(ref literal) => {
         match *literal {
           Literal::Function(ref func_type) => match func_type {
-            &Function::InCode(pos, ref parameters) => FunctionType::InCode(pos, parameters),
+            &Function::InCode(pos, ref parameters, scope) => FunctionType::InCode(pos, parameters, scope, None),
             &Function::Native(func) => FunctionType::Native(func)
           },
           _ => return Err(VMExecError::InvalidCast(val.clone(), "<function>".to_string(), expr_pos))
@@ -80,12 +81,13 @@ impl VMExec {
           None => 0
         }))
       },
-      &Value::Pointer(ref identifier, pos, scope) => match unsafe {(&*scope)}.get_var(identifier) {
-        Some(val) => self.cast_func(val, pos)?,
-        None => return Err(VMExecError::VariableNotDefined(identifier.to_string(), match pos {
-          Some(pos) => pos,
-          None => 0
-        }))
+      &Value::Pointer(ref identifier, pos, scope) => {
+        let val = unsafe { &*scope }.get_field(identifier, pos)?;
+        self.cast_func(val, pos)?
+      },
+      &Value::Method(instance, func) => match self.cast_func(func, expr_pos)? {
+        FunctionType::InCode(pos, parameters, scope, _) => FunctionType::InCode(pos, parameters, scope, Some(instance)),
+        func => func
       }
       _ => {
         return Err(VMExecError::Temp(5));
@@ -93,7 +95,7 @@ impl VMExec {
     })
   }
 
-  pub fn cast_int(&self, val: ValuePointer, expr_pos: Option<i32>) -> Result<i32, VMExecError> {
+  pub fn cast_int(&self, val: ValuePointer, expr_pos: Option<i32>) -> Result<i64, VMExecError> {
     let val = unsafe {
       &*val
     };
@@ -108,7 +110,7 @@ impl VMExec {
         }
       },
       &Value::Variable(ref identifier, pos) => self.match_var(identifier, scope, expr_pos, &Self::cast_int)?,
-      &Value::Pointer(ref identifier, pos, scope) => self.match_var(identifier, scope, expr_pos, &Self::cast_int)?,
+      &Value::Pointer(ref identifier, pos, scope) => self.cast_int(unsafe { &*scope }.get_field(identifier, pos)?, expr_pos)?,
       _ => {
         return Err(VMExecError::Temp(5));
       }
@@ -130,7 +132,7 @@ impl VMExec {
         }
       },
       &Value::Variable(ref identifier, pos) => self.match_var(identifier, scope, expr_pos, &Self::cast_struct)?,
-      &Value::Pointer(ref identifier, pos, scope) => self.match_var(identifier, scope, expr_pos, &Self::cast_struct)?,
+      &Value::Pointer(ref identifier, pos, scope) => self.cast_struct(unsafe { &*scope }.get_field(identifier, pos)?, expr_pos)?,
       _ => {
         return Err(VMExecError::Temp(5));
       }
@@ -153,7 +155,7 @@ impl VMExec {
         // }
       },
       &Value::Variable(ref identifier, pos) => self.match_var(identifier, scope, expr_pos, &Self::cast_instance)?,
-      &Value::Pointer(ref identifier, pos, scope) => self.match_var(identifier, scope, expr_pos, &Self::cast_instance)?,
+      &Value::Pointer(ref identifier, pos, scope) => self.cast_instance(unsafe { &*scope }.get_field(identifier, pos)?, expr_pos)?,
       _ => {
         return Err(VMExecError::InvalidCast(val.clone(), "<instance>".to_string(), expr_pos));
       }
@@ -0,0 +1,82 @@
+//! Turns a decoded program back into a readable listing, one instruction per line.
+
+use vm::{Program, BYTECODE_VERSION};
+use vm::verify::{read_instruction, Instruction, Operand, Kind};
+use span::Span;
+
+fn operand_to_string(kind: Kind, operand: &Operand, instruction: &Instruction, spans: &[Span]) -> String {
+  match (kind, operand) {
+    (Kind::Pos, &Operand::Int(pos)) => match spans.get(pos as usize) {
+      Some(span) => format!("@{}:{}", span.line, span.col),
+      None => String::from("@?")
+    },
+    (Kind::Jump, &Operand::Int(offset)) => format!("-> {:04}", instruction.end as i32 + offset + 1),
+    (_, &Operand::Int(int)) => format!("{}", int),
+    (_, &Operand::Str(ref s)) => format!("{:?}", s),
+    (_, &Operand::Num(num)) => format!("{:?}", num),
+    (_, &Operand::Int64(int)) => format!("{}", int),
+    (_, &Operand::Bool(b)) => format!("{}", b)
+  }
+}
+
+/// Lists the header and every instruction of the program with its byte offset and decoded operands.
+/// Jumps are resolved to the absolute offset execution continues at,
+/// debug positions are shown as the `@line:col` of their span.
+pub fn disassemble(program: &Program) -> String {
+  let module = &program.module;
+  let operations = &program.operations;
+
+  let mut out = format!("version {}{}\n", BYTECODE_VERSION, if module.is_debug { ", debug" } else { "" });
+  out += &format!("code: {} bytes\n", module.code.len());
+  out += &format!("constants: {}\n", module.constants.len());
+  out += &format!("symbols: {}\n", module.symbols.len());
+  if module.is_debug {
+    out += &format!("file: {:?}, {} spans\n", module.file, module.spans.len());
+  }
+  if let Some(ref source) = module.source {
+    out += &format!("source: {} bytes, {} line entries\n", source.len(), module.lines.len());
+  }
+  out.push('\n');
+
+  let source_lines: Vec<&str> = match module.source {
+    Some(ref source) => source.split('\n').collect(),
+    None => Vec::new()
+  };
+
+  let mut line = None;
+  let mut i = 0;
+  while i < operations.len() {
+    // the source line this code was built from, when it changes
+    let curr_line = module.line_at(i);
+    if curr_line != line {
+      if let Some(n) = curr_line {
+        if let Some(text) = source_lines.get(n as usize - 1) {
+          out += &format!("      ; {}: {}\n", n, text.trim());
+        }
+      }
+      line = curr_line;
+    }
+
+    let instruction = match read_instruction(operations, i, module.is_debug) {
+      Ok(instruction) => instruction,
+      Err(err) => {
+        // keep going byte by byte, the rest might still make sense
+        out += &format!("{:04}  <{:?}>\n", i, err);
+        i += 1;
+        continue;
+      }
+    };
+
+    let mut text = format!("{:04}  {:?}", instruction.start, instruction.code);
+    for &(kind, ref operand) in &instruction.operands {
+      text += " ";
+      text += &operand_to_string(kind, operand, &instruction, &module.spans);
+    }
+
+    out += &text;
+    out.push('\n');
+    i = instruction.end + 1;
+  }
+
+  out
+}
@@ -1,11 +1,20 @@
 use vm::*;
 use vm::OPCode::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::{error, fmt};
 use self::cast::{FunctionType, NativeScope, NativePars, NativeReturn};
+use self::verify::{verify, VerifyError};
+use self::format::FormatError;
+use span::{Span, Source};
 
-use self::native::{value_to_string, add_func, input_func, print_func, format_func};
+use self::native::{value_to_string, add_func, input_func, print_func, format_func, gc_func};
+use self::native::{len_func, push_func, pop_func, slice_func, keys_func, values_func, has_func, remove_func};
 
-const STACK_SIZE: usize = 512;
+// limits of a VMExec unless set with the builder
+const DEFAULT_STACK_SIZE: usize = 1 << 20;
+const DEFAULT_MAX_DEPTH: usize = 1 << 16;
 
 #[derive(Debug)]
 pub enum VMExecError {
@@ -15,25 +24,129 @@ pub enum VMExecError {
   InvalidOPCode(String),
   UnsupportedOPCode(String),
   VariableNotDefined(String, i32),
-
-  // STACK_LIMIT, pos
-  StackLimitReached(i32, i32),
+  // field, pos
+  FieldNotDefined(String, i32),
+
+  // limit of the stack, calls in progress (innermost first)
+  StackOverflow(usize, Vec<CallSite>),
+  // values to pop, values on the stack, pos
+  StackUnderflow(i64, usize, Option<i32>),
+  // pos
+  ReturnWithoutCall(Option<i32>),
+  ContinueWithoutLoop(Option<i32>),
+  // value, pos
+  NotAnIterator(Value, Option<i32>),
+  // operation, pos
+  JumpStackUnderflow(OPCode, Option<i32>),
+  // operation, index of the current scope, scopes on the stack
+  ScopeOutOfBounds(OPCode, usize, usize),
 
   // func_pars, func_pars_len, args_len, func_abs_pos, pos
   ArgumentMismatch(Vec<String>, i32, i32, i32, i32),
 
+  FormatErr(FormatError),
+  VerifyErr(VerifyError),
+  ProgramOutOfBounds(i32),
+
   // position in bytecode
   InvalidOperationContent(usize),
   InvalidIdentifier(String),
   InvalidCast(Value, String, Option<i32>),
 
+  // operation, pos
+  IntegerOverflow(OPCode, i32),
+  DivisionByZero(OPCode, i32),
+
+  // index, len, pos
+  IndexOutOfBounds(i64, usize, Option<i32>),
+  // key, pos
+  KeyNotFound(String, Option<i32>),
+  // length of the string, times it's repeated, pos
+  StringTooLong(usize, i64, i32),
+
   Temp(i32)
 }
 
+impl VMExecError {
+  /// The debug position of the error, an index into the span table of the running code.
+  pub fn pos(&self) -> Option<i32> {
+    match *self {
+      VMExecError::UnsupportedOperation(_, _, _, pos) |
+      VMExecError::UnsupportedValueOperation(_, _, _, pos) |
+      VMExecError::VariableNotDefined(_, pos) |
+      VMExecError::FieldNotDefined(_, pos) |
+      VMExecError::ArgumentMismatch(_, _, _, _, pos) |
+      VMExecError::IntegerOverflow(_, pos) |
+      VMExecError::DivisionByZero(_, pos) |
+      VMExecError::StringTooLong(_, _, pos) => Some(pos),
+      VMExecError::InvalidCast(_, _, pos) |
+      VMExecError::StackUnderflow(_, _, pos) |
+      VMExecError::ReturnWithoutCall(pos) |
+      VMExecError::ContinueWithoutLoop(pos) |
+      VMExecError::NotAnIterator(_, pos) |
+      VMExecError::JumpStackUnderflow(_, pos) |
+      VMExecError::IndexOutOfBounds(_, _, pos) |
+      VMExecError::KeyNotFound(_, pos) => pos,
+      VMExecError::StackOverflow(_, ref trace) => trace.first().and_then(|site| site.pos),
+      _ => None
+    }
+  }
+
+  // natives don't know where they were called from, so their errors get the position of the call
+  fn at_call(self, pos: Option<i32>) -> Self {
+    match (self, pos) {
+      (VMExecError::IntegerOverflow(op, -1), Some(pos)) => VMExecError::IntegerOverflow(op, pos),
+      (VMExecError::InvalidCast(val, to, None), pos) => VMExecError::InvalidCast(val, to, pos),
+      (VMExecError::IndexOutOfBounds(index, len, None), pos) => VMExecError::IndexOutOfBounds(index, len, pos),
+      (VMExecError::KeyNotFound(key, None), pos) => VMExecError::KeyNotFound(key, pos),
+      (err, _) => err
+    }
+  }
+}
+
+impl fmt::Display for VMExecError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      VMExecError::UnsupportedOperation(ref first, ref second, ref op_code, _) =>
+        write!(f, "operation {:?} not supported for types {:?} and {:?}", op_code, first, second),
+      VMExecError::UnsupportedValueOperation(ref first, ref second, ref op_code, _) =>
+        write!(f, "operation {:?} not supported for values {:?} and {:?}", op_code, first, second),
+      VMExecError::InvalidOPCode(ref op) => write!(f, "invalid opcode {}", op),
+      VMExecError::UnsupportedOPCode(ref op) => write!(f, "unsupported opcode {}", op),
+      VMExecError::VariableNotDefined(ref identifier, _) => write!(f, "variable {:?} not defined", identifier),
+      VMExecError::FieldNotDefined(ref identifier, _) => write!(f, "field {:?} not defined on the instance", identifier),
+      VMExecError::StackOverflow(limit, ref trace) =>
+        write!(f, "stack overflow, more than {} entries with {} call{} in progress", limit, trace.len(), if trace.len() == 1 {""} else {"s"}),
+      VMExecError::StackUnderflow(count, len, _) => write!(f, "{} values to pop, but {} on the stack", count, len),
+      VMExecError::ReturnWithoutCall(_) => write!(f, "return outside of a function call"),
+      VMExecError::ContinueWithoutLoop(_) => write!(f, "continue outside of a loop"),
+      VMExecError::NotAnIterator(ref val, _) => write!(f, "{:?} is not an iterator", val),
+      VMExecError::JumpStackUnderflow(ref op_code, _) => write!(f, "{:?} with nothing on the jump stack", op_code),
+      VMExecError::ScopeOutOfBounds(ref op_code, scopei, len) =>
+        write!(f, "{:?} moves out of the scope stack, from scope {} of {}", op_code, scopei, len),
+      VMExecError::ArgumentMismatch(ref func_pars, func_pars_len, args_len, func_abs_pos, _) =>
+        write!(f, "<function ({:?}) at {}> takes {} parameter{}, but {} {} given", func_pars, func_abs_pos, func_pars_len, if func_pars_len == 1 {""} else {"s"}, args_len, if args_len == 1 {"was"} else {"were"}),
+      VMExecError::FormatErr(ref err) => write!(f, "invalid program: {:?}", err),
+      VMExecError::VerifyErr(ref err) => write!(f, "invalid program: {:?}", err),
+      VMExecError::ProgramOutOfBounds(op_i) => write!(f, "program counter {} out of bounds", op_i),
+      VMExecError::InvalidCast(ref literal, ref to, _) => write!(f, "invalid cast: {:?} to {}", literal, to),
+      VMExecError::IntegerOverflow(ref op_code, _) => write!(f, "integer overflow in operation {:?}", op_code),
+      VMExecError::DivisionByZero(ref op_code, _) => write!(f, "integer division by zero in operation {:?}", op_code),
+      VMExecError::IndexOutOfBounds(index, len, _) => write!(f, "index {} out of bounds for length {}", index, len),
+      VMExecError::KeyNotFound(ref key, _) => write!(f, "key {:?} not found", key),
+      VMExecError::StringTooLong(len, count, _) =>
+        write!(f, "string of length {} repeated {} times is longer than {} bytes", len, count, MAX_STRING_LEN),
+      _ => write!(f, "{:?}", self)
+    }
+  }
+}
+
+impl error::Error for VMExecError {}
+
 #[derive(Clone, Debug)]
 pub enum Literal {
   Num(f64),
-  Int(i32),
+  Int(i64),
   Bool(bool),
   String(String),
   Function(Function),
@@ -41,6 +154,9 @@ pub enum Literal {
   // op_i pos, debug_pos
   Structure(i32, Option<i32>),
 
+  // start, end (exclusive)
+  Range(i64, i64),
+
   Nil
 }
 
@@ -49,34 +165,193 @@ pub enum Value {
   Variable(String, Option<i32>),
   Pointer(String, Option<i32>, *mut Scope),
   Instance(*mut Scope),
+  // a function read from an instance, `self` is the instance wherever it's called
+  Method(*mut Scope, *const Value),
+  Array(Array),
+  Map(Map),
+  // state of a for loop
+  Iterator(RefCell<IterState>),
   // Reference(*const Value),
   Literal(Literal),
   None,
 }
 
+// shared by every copy of the value, mutated in place
+pub type Array = Rc<RefCell<Vec<*const Value>>>;
+pub type Map = Rc<RefCell<BTreeMap<String, *const Value>>>;
+
+#[derive(Clone, Debug)]
+pub enum IterState {
+  // next, end
+  Range(i64, i64),
+  Chars(Vec<char>, usize),
+  Array(Array, usize),
+  // keys at the start of the loop
+  Keys(Vec<String>, usize),
+  // instance with a __next__ method
+  Instance(*const Value)
+}
+
 #[derive(Clone, Debug)]
 pub enum Function {
   Native(fn(NativeScope, NativePars) -> NativeReturn),
 
-  // op_ipos , parameters
-  InCode(i32, Vec<String>)
+  // op_ipos , parameters, scope it was defined in
+  InCode(i32, Vec<String>, *mut Scope)
+}
+
+fn literal_eq(first: &Literal, second: &Literal) -> bool {
+  match (first, second) {
+    (&Literal::Num(first), &Literal::Num(second)) => first == second,
+    (&Literal::Int(first), &Literal::Int(second)) => first == second,
+    (&Literal::Num(first), &Literal::Int(second)) |
+    (&Literal::Int(second), &Literal::Num(first)) => first == second as f64,
+    (&Literal::Bool(first), &Literal::Bool(second)) => first == second,
+    (&Literal::String(ref first), &Literal::String(ref second)) => first == second,
+    (&Literal::Nil, &Literal::Nil) => true,
+    (&Literal::Function(ref first), &Literal::Function(ref second)) => match (first, second) {
+      (&Function::Native(first), &Function::Native(second)) => first as usize == second as usize,
+      (&Function::InCode(first, _, first_scope), &Function::InCode(second, _, second_scope)) => first == second && first_scope == second_scope,
+      _ => false
+    },
+    (&Literal::Structure(first, _), &Literal::Structure(second, _)) => first == second,
+    (&Literal::Range(first_start, first_end), &Literal::Range(second_start, second_end)) => first_start == second_start && first_end == second_end,
+    _ => false
+  }
+}
+
+// identity for instances, value equality for literals
+fn value_eq(first: &Value, second: &Value) -> bool {
+  match (first, second) {
+    (&Value::Literal(ref first), &Value::Literal(ref second)) => literal_eq(first, second),
+    (&Value::Instance(first), &Value::Instance(second)) => first == second,
+    (&Value::Method(first, first_func), &Value::Method(second, second_func)) => first == second && unsafe { value_eq(&*first_func, &*second_func) },
+    (&Value::Array(ref first), &Value::Array(ref second)) => Rc::ptr_eq(first, second),
+    (&Value::Map(ref first), &Value::Map(ref second)) => Rc::ptr_eq(first, second),
+    _ => false
+  }
+}
+
+// longest string `*` builds, a huge count would otherwise abort on the allocation
+const MAX_STRING_LEN: usize = 1 << 30;
+
+fn repeat_string(string: &str, count: i64, pos: i32) -> Result<String, VMExecError> {
+  let times = count.max(0) as u64;
+  match (string.len() as u64).checked_mul(times) {
+    Some(len) if len <= MAX_STRING_LEN as u64 => Ok(string.repeat(times as usize)),
+    _ => Err(VMExecError::StringTooLong(string.len(), count, pos))
+  }
+}
+
+// collect when this many values and scopes are allocated, grows with the live set
+const GC_THRESHOLD: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcStats {
+  pub collections: usize,
+  pub freed_values: usize,
+  pub freed_scopes: usize,
+  pub live_values: usize,
+  pub live_scopes: usize
 }
 
 pub struct Root {
   pool: Vec<Box<Value>>,
-  scopes: Vec<Box<Scope>>
+  scopes: Vec<Box<Scope>>,
+
+  next_gc: usize,
+  gc_requested: bool,
+  stats: GcStats
 }
 
 impl Root {
   pub fn new() -> Self {
     Self {
       pool: Vec::new(),
-      scopes: Vec::new()
+      scopes: Vec::new(),
+
+      next_gc: GC_THRESHOLD,
+      gc_requested: false,
+      stats: GcStats::default()
     }
   }
 
-  pub fn gc() {
-    // todo
+  pub fn should_gc(&self) -> bool {
+    self.gc_requested || self.pool.len() + self.scopes.len() >= self.next_gc
+  }
+
+  // collects at the next safe point, used by natives that can't see the roots
+  pub fn request_gc(&mut self) {
+    self.gc_requested = true;
+  }
+
+  /// Mark and sweep, everything not reachable from `values` or `scopes` is freed.
+  /// Must only be called between operations, when every live value is on a stack or in a scope.
+  pub fn gc(&mut self, values: Vec<*const Value>, scopes: Vec<*mut Scope>) {
+    let mut marked_values: HashSet<*const Value> = HashSet::new();
+    let mut marked_scopes: HashSet<*mut Scope> = HashSet::new();
+
+    let mut value_queue = values;
+    let mut scope_queue = scopes;
+
+    while !value_queue.is_empty() || !scope_queue.is_empty() {
+      while let Some(val) = value_queue.pop() {
+        if !marked_values.insert(val) {
+          continue;
+        }
+
+        match unsafe { &*val } {
+          &Value::Pointer(_, _, scope) |
+          &Value::Instance(scope) |
+          &Value::Literal(Literal::Function(Function::InCode(_, _, scope))) => scope_queue.push(scope),
+          &Value::Method(scope, func) => {
+            scope_queue.push(scope);
+            value_queue.push(func);
+          },
+          &Value::Array(ref elements) => value_queue.extend(elements.borrow().iter()),
+          &Value::Map(ref entries) => value_queue.extend(entries.borrow().values()),
+          &Value::Iterator(ref state) => match *state.borrow() {
+            IterState::Array(ref elements, _) => value_queue.extend(elements.borrow().iter()),
+            IterState::Instance(val) => value_queue.push(val),
+            _ => {}
+          },
+          _ => {}
+        }
+      }
+
+      while let Some(scope) = scope_queue.pop() {
+        if !marked_scopes.insert(scope) {
+          continue;
+        }
+
+        let scope = unsafe { &*scope };
+        value_queue.extend(scope.variables.values());
+        scope_queue.extend(scope.parent.iter().chain(scope.base.iter()).chain(scope.owner.iter()));
+      }
+    }
+
+    let values_before = self.pool.len();
+    let scopes_before = self.scopes.len();
+
+    self.pool.retain(|val| marked_values.contains(&(&**val as *const Value)));
+    self.scopes.retain(|scope| marked_scopes.contains(&(&**scope as *const Scope as *mut Scope)));
+
+    self.stats.collections += 1;
+    self.stats.freed_values += values_before - self.pool.len();
+    self.stats.freed_scopes += scopes_before - self.scopes.len();
+    self.stats.live_values = self.pool.len();
+    self.stats.live_scopes = self.scopes.len();
+
+    self.gc_requested = false;
+    self.next_gc = GC_THRESHOLD.max((self.pool.len() + self.scopes.len()) * 2);
+  }
+
+  // for natives, the value has to be reachable before the next operation or it is collected
+  pub fn alloc(&mut self, val: Value) -> *const Value {
+    let val = Box::new(val);
+    let val_point = &*val as *const Value;
+    self.pool.push(val);
+    val_point
   }
 }
 
@@ -84,6 +359,10 @@ pub struct Scope {
   root: *mut Root,
   parent: Option<*mut Scope>,
   variables: HashMap<String, *const Value>,
+
+  // for instances, the instance of the struct it extends and the outermost instance it's a base of
+  base: Option<*mut Scope>,
+  owner: Option<*mut Scope>
 }
 
 impl Scope {
@@ -91,10 +370,17 @@ impl Scope {
     Self {
       root,
       parent,
-      variables: HashMap::new()
+      variables: HashMap::new(),
+
+      base: None,
+      owner: None
     }
   }
 
+  pub fn get_root(&self) -> *mut Root {
+    self.root
+  }
+
   fn get_var_directly(&self, identifier: &str) -> Option<*const Value> {
     match self.variables.get(identifier) {
       Some(val) => Some(*val),
@@ -151,6 +437,99 @@ impl Scope {
   pub fn set_var_directly(&mut self, identifier: &str, val: *const Value) {
     self.variables.insert(identifier.to_string(), val);
   }
+
+  /// A field of the instance this is the scope of, functions are bound to the instance.
+  /// Unlike variables, fields are never looked up in the scopes around the instance, only in its bases.
+  pub fn get_field(&self, identifier: &str, pos: Option<i32>) -> Result<*const Value, VMExecError> {
+    match self.find_field(identifier) {
+      Some(val) => Ok(self.bind(val)),
+      None => Err(VMExecError::FieldNotDefined(identifier.to_string(), pos.unwrap_or(0)))
+    }
+  }
+
+  // fields of the instance override the ones of its bases
+  fn find_field(&self, identifier: &str) -> Option<*const Value> {
+    match self.get_var_directly(identifier) {
+      Some(val) => Some(val),
+      None => match self.base {
+        Some(base) => unsafe { (*base).find_field(identifier) },
+        None => None
+      }
+    }
+  }
+
+  // only changes existing fields, where they're defined, new ones are added with `let instance.field = val`
+  fn set_field(&mut self, identifier: &str, val: *const Value, pos: Option<i32>) -> Result<(), VMExecError> {
+    if self.get_var_directly(identifier).is_some() {
+      self.variables.insert(identifier.to_string(), val);
+      return Ok(());
+    }
+
+    match self.base {
+      Some(base) => unsafe { (*base).set_field(identifier, val, pos) },
+      None => Err(VMExecError::FieldNotDefined(identifier.to_string(), pos.unwrap_or(0)))
+    }
+  }
+
+  // a method of this instance for in-code functions, methods of other instances stay bound to those.
+  // methods of a base are methods of the instance it's the base of, so overrides apply in them too
+  fn bind(&self, val: *const Value) -> *const Value {
+    match unsafe { &*val } {
+      &Value::Literal(Literal::Function(Function::InCode(..))) => unsafe {
+        let instance = self.owner.unwrap_or(self as *const Scope as *mut Scope);
+        (*self.root).alloc(Value::Method(instance, val))
+      },
+      _ => val
+    }
+  }
+}
+
+/// A function or struct call in progress, for the stack trace of an error.
+#[derive(Clone, Debug)]
+pub struct CallSite {
+  pub name: Option<String>, // the variable it was called through
+  pub func: i32, // op_i of the function or struct body
+  pub pos: Option<i32> // debug position of the call
+}
+
+// the name a function or struct is called through, none for an expression like `(fn() {})()`
+fn call_name(val: *const Value) -> Option<String> {
+  match unsafe { &*val } {
+    &Value::Variable(ref name, _) | &Value::Pointer(ref name, _, _) => Some(name.to_owned()),
+    _ => None
+  }
+}
+
+// state to restore when returning from an in-code function
+struct CallFrame {
+  stacki: usize,
+  jump_stacki: usize,
+  scope_stacki: usize,
+  site: CallSite
+}
+
+/// Creates a `VMExec` with other stack limits than the default.
+pub struct VMExecBuilder {
+  stack_size: usize,
+  max_depth: usize
+}
+
+impl VMExecBuilder {
+  /// Most values on the value stack at once.
+  pub fn stack_size(mut self, stack_size: usize) -> Self {
+    self.stack_size = stack_size;
+    self
+  }
+
+  /// Deepest nesting of calls, scopes and loops.
+  pub fn max_depth(mut self, max_depth: usize) -> Self {
+    self.max_depth = max_depth;
+    self
+  }
+
+  pub fn build(self) -> VMExec {
+    VMExec::from_builder(self)
+  }
 }
 
 pub struct VMExec {
@@ -160,16 +539,23 @@ pub struct VMExec {
   // scopes
 
   // variables: HashMap<String, *const Value>,
-  root: Root,
+  root: Box<Root>, // boxed, scopes keep a pointer to it
 
-  stack: [*const Value; 512],
-  stacki: usize,
-  jump_stack: [i32; 512],
-  jump_stacki: usize,
-  scope_stack: [Option<*mut Scope>; 512],
+  stack: Vec<*const Value>,
+  jump_stack: Vec<i32>,
+  // scope_stack[scope_stacki] is the current scope, SCOPE_BACK leaves the ones above it
+  scope_stack: Vec<*mut Scope>,
   scope_stacki: usize,
+  call_stack: Vec<CallFrame>,
+  // call_stack when the last exec failed
+  trace: Vec<CallSite>,
 
-  pub query: String,
+  stack_size: usize,
+  max_depth: usize,
+
+  // the code that has been run, with the spans debug positions refer to
+  pub sources: Vec<Source>,
+  pub spans: Vec<Span>,
 
   pub is_debug: bool,
   pub contains_code: bool
@@ -177,7 +563,18 @@ pub struct VMExec {
 
 impl VMExec {
   pub fn new() -> Self {
-    let root = Root::new();
+    Self::builder().build()
+  }
+
+  pub fn builder() -> VMExecBuilder {
+    VMExecBuilder {
+      stack_size: DEFAULT_STACK_SIZE,
+      max_depth: DEFAULT_MAX_DEPTH
+    }
+  }
+
+  fn from_builder(builder: VMExecBuilder) -> Self {
+    let root = Box::new(Root::new());
     
     let mut this = Self {
       op_i: 0,
@@ -186,29 +583,42 @@ impl VMExec {
       // variables: HashMap::new(),
       root,
 
-      stack: [&Value::None; 512],
-      stacki: 0,
-      jump_stack: [0; 512],
-      jump_stacki: 0,
-      scope_stack: [None; 512],
+      stack: Vec::new(),
+      jump_stack: Vec::new(),
+      scope_stack: Vec::new(),
       scope_stacki: 0,
+      call_stack: Vec::new(),
+      trace: Vec::new(),
+
+      stack_size: builder.stack_size,
+      max_depth: builder.max_depth,
 
-      query: String::from(""),
+      sources: Vec::new(),
+      spans: Vec::new(),
 
       is_debug: false,
       contains_code: false,
     };
 
-    let mut scope = Box::new(Scope::new(&mut this.root as *mut Root, None));
+    let mut scope = Box::new(Scope::new(&mut *this.root as *mut Root, None));
     let scope_point = &mut *scope as *mut Scope;
     this.root.scopes.push(scope);
-    this.scope_stack[0] = Some(scope_point);
+    this.scope_stack.push(scope_point);
 
     let mut funcs: HashMap<&'static str, fn(NativeScope, NativePars) -> NativeReturn> = HashMap::new();
     funcs.insert("add", add_func);
     funcs.insert("input", input_func);
     funcs.insert("print", print_func);
     funcs.insert("format", format_func);
+    funcs.insert("gc", gc_func);
+    funcs.insert("len", len_func);
+    funcs.insert("push", push_func);
+    funcs.insert("pop", pop_func);
+    funcs.insert("slice", slice_func);
+    funcs.insert("keys", keys_func);
+    funcs.insert("values", values_func);
+    funcs.insert("has", has_func);
+    funcs.insert("remove", remove_func);
 
     for (k, i) in funcs {
       let func = Box::new(Value::Literal(Literal::Function(Function::Native(i))));
@@ -226,17 +636,30 @@ impl VMExec {
     if !append {
       self.program = Vec::new();
       self.op_i = 0;
-      self.query = String::from("");
+      self.sources.clear();
+      self.spans.clear();
     }
-    self.stack = [&Value::None; 512];
-    self.stacki = 0;
-    self.jump_stack = [0; 512];
-    self.jump_stacki = 0;
+    self.stack.clear();
+    self.jump_stack.clear();
+    self.scope_stack.truncate(1); // the global scope
     self.scope_stacki = 0;
+    self.call_stack.clear();
+    self.trace.clear();
+  }
+
+  /// Runs the collector with everything reachable from the stacks as roots.
+  pub fn gc(&mut self) {
+    let values = self.stack.clone();
+    let scopes = self.scope_stack[0..self.scope_stacki + 1].to_vec();
+
+    self.root.gc(values, scopes);
+  }
 
-    let mut scope = self.scope_stack[0];
-    self.scope_stack = [None; 512];
-    self.scope_stack[0] = scope;
+  pub fn gc_stats(&self) -> GcStats {
+    let mut stats = self.root.stats;
+    stats.live_values = self.root.pool.len();
+    stats.live_scopes = self.root.scopes.len();
+    stats
   }
 
   fn consume(&mut self) -> u8 {
@@ -249,73 +672,93 @@ impl VMExec {
     &self.program[self.op_i as usize]
   }
 
-  fn stack_push(&mut self, val: *const Value) {
-    self.stack[self.stacki] = val;
-    self.stacki += 1;
+  /// The calls that were in progress when the last `exec` failed, innermost first.
+  pub fn trace(&self) -> &[CallSite] {
+    &self.trace
+  }
+
+  // the calls in progress, innermost first
+  fn call_trace(&self) -> Vec<CallSite> {
+    self.call_stack.iter()
+      .rev()
+      .map(|frame| frame.site.clone())
+      .collect()
+  }
+
+  fn stack_push(&mut self, val: *const Value) -> Result<(), VMExecError> {
+    if self.stack.len() >= self.stack_size {
+      return Err(VMExecError::StackOverflow(self.stack_size, self.call_trace()));
+    }
+    self.stack.push(val);
+    Ok(())
   }
 
   fn stack_pop(&mut self) -> *const Value {
-    if self.stacki <= 0 {
-      return NIL;
+    match self.stack.pop() {
+      Some(val) => val,
+      None => NIL
     }
-    self.stacki -= 1;
-    self.stack[self.stacki]
   }
 
-  fn stack_peek(&self) -> *const Value {
-    if self.stacki <= 0 {
-      return NIL;
+  // how many values an instruction pops, a corrupt program can make it negative or larger than the stack
+  fn stack_count(&self, count: i64, pos: Option<i32>) -> Result<usize, VMExecError> {
+    if count < 0 || count as usize > self.stack.len() {
+      return Err(VMExecError::StackUnderflow(count, self.stack.len(), pos));
     }
-    let val = self.stack[self.stacki - 1];
-    unsafe {
-      match *val {
-        Value::None => NIL,
-        _ => val
-      }
+    Ok(count as usize)
+  }
+
+  fn stack_peek(&self) -> *const Value {
+    match self.stack.last() {
+      Some(&val) => unsafe {
+        match *val {
+          Value::None => NIL,
+          _ => val
+        }
+      },
+      None => NIL
     }
   }
 
-  fn jump_stack_push(&mut self, val: i32) {
-    self.jump_stack[self.jump_stacki] = val;
-    self.jump_stacki += 1;
+  fn jump_stack_push(&mut self, val: i32) -> Result<(), VMExecError> {
+    if self.jump_stack.len() >= self.max_depth {
+      return Err(VMExecError::StackOverflow(self.max_depth, self.call_trace()));
+    }
+    self.jump_stack.push(val);
+    Ok(())
   }
 
-  fn jump_stack_pop(&mut self) -> Result<i32, VMExecError> {
-    if self.jump_stacki <= 0 {
-      return Err(VMExecError::Temp(69));
+  fn jump_stack_pop(&mut self, code: OPCode, pos: Option<i32>) -> Result<i32, VMExecError> {
+    match self.jump_stack.pop() {
+      Some(val) => Ok(val),
+      None => Err(VMExecError::JumpStackUnderflow(code, pos))
     }
-    self.jump_stacki -= 1;
-    Ok(self.jump_stack[self.jump_stacki])
   }
 
   pub fn scope_stack_peek(&self) -> Result<*mut Scope, VMExecError> {
     // println!("scopei: {}, {:?}", self.scope_stacki, self.scope_stack[self.scope_stacki]);
 
-    match self.scope_stack[self.scope_stacki] {
-      Some(val) => Ok(val),
+    match self.scope_stack.get(self.scope_stacki) {
+      Some(&val) => Ok(val),
       None => Err(VMExecError::Temp(0))
     }
   }
 
-  fn scope_stack_push(&mut self, val: *mut Scope, pos: Option<i32>) -> Result<(), VMExecError> {
-    self.scope_stacki += 1;
-
-    if self.scope_stacki < STACK_SIZE - 1 {
-      self.scope_stack[self.scope_stacki] = Some(val);
-      Ok(())
-    } else {
-      Err(VMExecError::StackLimitReached(STACK_SIZE as i32, match pos {
-        Some(val) => val,
-        None => 0
-      }))
+  fn scope_stack_push(&mut self, val: *mut Scope) -> Result<(), VMExecError> {
+    if self.scope_stacki + 1 >= self.max_depth {
+      return Err(VMExecError::StackOverflow(self.max_depth, self.call_trace()));
     }
+    self.scope_stacki += 1;
+    self.scope_stack.truncate(self.scope_stacki);
+    self.scope_stack.push(val);
+    Ok(())
   }
 
   fn scope_stack_pop(&mut self) -> Result<*mut Scope, VMExecError> {
     if self.scope_stacki <= 0 {
       return Err(VMExecError::Temp(1));
     }
-    let to_return = self.scope_stack[self.scope_stacki];
+    let to_return = self.scope_stack.get(self.scope_stacki).cloned();
     self.scope_stacki -= 1;
     match to_return {
       Some(val) => Ok(val),
@@ -359,6 +802,82 @@ impl VMExec {
     }
   }
 
+  // resolves variables and pointers to the value they refer to
+  fn get_value(&self, val: *const Value, pos: Option<i32>) -> Result<*const Value, VMExecError> {
+    self.get_value_in(val, self.scope_stack_peek()?, pos)
+  }
+
+  fn get_value_in(&self, val: *const Value, scope: *mut Scope, pos: Option<i32>) -> Result<*const Value, VMExecError> {
+    let (identifier, pos) = match unsafe { &*val } {
+      &Value::Variable(ref identifier, var_pos) => (identifier, var_pos.or(pos)),
+      &Value::Pointer(ref identifier, field_pos, scope) => return unsafe { &*scope }.get_field(identifier, field_pos.or(pos)),
+      _ => return Ok(val)
+    };
+
+    match unsafe { &*scope }.get_var(identifier) {
+      Some(val) => Ok(val),
+      None => Err(VMExecError::VariableNotDefined(identifier.to_string(), match pos {
+        Some(pos) => pos,
+        None => 0
+      }))
+    }
+  }
+
+  fn get_index(&self, val: *const Value, len: usize, pos: Option<i32>) -> Result<usize, VMExecError> {
+    let val = self.get_value(val, pos)?;
+    let index = match unsafe { &*val } {
+      &Value::Literal(Literal::Num(num)) if num.fract() == 0.0 => num as i64,
+      &Value::Literal(Literal::Int(int)) => int,
+      _ => return Err(VMExecError::InvalidCast(unsafe { (*val).clone() }, "<index>".to_string(), pos))
+    };
+
+    if index < 0 || index as usize >= len {
+      return Err(VMExecError::IndexOutOfBounds(index, len, pos));
+    }
+
+    Ok(index as usize)
+  }
+
+  // next value of a builtin iterator, None when exhausted
+  fn iter_next(&mut self, state: &RefCell<IterState>) -> Option<*const Value> {
+    match *state.borrow_mut() {
+      IterState::Range(ref mut next, end) => if *next < end {
+        *next += 1;
+        Some(self.root.alloc(Value::Literal(Literal::Int(*next - 1))))
+      } else {
+        None
+      },
+      IterState::Chars(ref chars, ref mut i) => if *i < chars.len() {
+        *i += 1;
+        Some(self.root.alloc(Value::Literal(Literal::String(chars[*i - 1].to_string()))))
+      } else {
+        None
+      },
+      IterState::Array(ref elements, ref mut i) => match elements.borrow().get(*i) {
+        Some(val) => {
+          *i += 1;
+          Some(*val)
+        },
+        None => None
+      },
+      IterState::Keys(ref keys, ref mut i) => if *i < keys.len() {
+        *i += 1;
+        Some(self.root.alloc(Value::Literal(Literal::String(keys[*i - 1].to_string()))))
+      } else {
+        None
+      },
+      IterState::Instance(_) => None
+    }
+  }
+
+  fn get_key(&self, val: *const Value, pos: Option<i32>) -> Result<String, VMExecError> {
+    let val = self.get_value(val, pos)?;
+    match unsafe { &*val } {
+      &Value::Literal(Literal::String(ref key)) => Ok(key.to_string()),
+      val => Err(VMExecError::InvalidCast(val.clone(), "<string>".to_string(), pos))
+    }
+  }
+
   fn literal_operation(&mut self, val1f: *const Value, val2f: *const Value, operation: &OPCode, pos: Option<i32>) -> Result<*const Value, VMExecError> {
     let get_pos = || {
       match pos {
@@ -390,13 +909,7 @@ impl VMExec {
             }))
           }
         } else if let &Value::Pointer(ref identifier, pos, scope) = &*val1f {
-          val1 = match (&*scope).get_var(identifier) {
-            Some(val) => val,
-            None => return Err(VMExecError::VariableNotDefined(identifier.to_string(), match pos {
-              Some(pos) => pos,
-              None => 0
-            }))
-          }
+          val1 = (&*scope).get_field(identifier, pos)?;
         }
       }
       if let &Value::Variable(ref identifier, pos) = &*val2f {
@@ -408,13 +921,7 @@ impl VMExec {
           }))
         }
       } else if let &Value::Pointer(ref identifier, pos, scope) = &*val2f {
-        val2 = match (&*scope).get_var(identifier) {
-          Some(val) => val,
-          None => return Err(VMExecError::VariableNotDefined(identifier.to_string(), match pos {
-            Some(pos) => pos,
-            None => 0
-          }))
-        }
+        val2 = (&*scope).get_field(identifier, pos)?;
       }
       // println!("{:?}, {:?}", *val1, *val2);
     }
@@ -422,8 +929,105 @@ impl VMExec {
     unsafe {
       match (&*val1, &*val2) {
         (&Value::Literal(ref lit1), &Value::Literal(ref lit2)) => {
+          // an int and a float operate as floats
+          let promoted = match (lit1, lit2) {
+            (&Literal::Int(first), &Literal::Num(second)) => Some((Literal::Num(first as f64), Literal::Num(second))),
+            (&Literal::Num(first), &Literal::Int(second)) => Some((Literal::Num(first), Literal::Num(second as f64))),
+            _ => None
+          };
+          let (lit1, lit2) = match promoted {
+            Some((ref first, ref second)) => (first, second),
+            None => (lit1, lit2)
+          };
+
+          let overflow = || VMExecError::IntegerOverflow(*operation, get_pos());
+          let division_by_zero = || VMExecError::DivisionByZero(*operation, get_pos());
+
           let res: Value = match(lit1, lit2, operation) {
 
+            // INTEGER OPERATIONS
+            (&Literal::Int(first), &Literal::Int(second), &ADD) => {
+              Value::Literal(Literal::Int(first.checked_add(second).ok_or_else(overflow)?))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &SUB) => {
+              Value::Literal(Literal::Int(first.checked_sub(second).ok_or_else(overflow)?))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &MULTIPLY) => {
+              Value::Literal(Literal::Int(first.checked_mul(second).ok_or_else(overflow)?))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &DIVIDE) => {
+              Value::Literal(Literal::Num(first as f64 / second as f64))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &INT_DIVIDE) => {
+              if second == 0 {
+                return Err(division_by_zero());
+              }
+              let quotient = first.checked_div(second).ok_or_else(overflow)?;
+              Value::Literal(Literal::Int(if first % second != 0 && (first < 0) != (second < 0) {
+                quotient - 1
+              } else {
+                quotient
+              }))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &MOD) => {
+              if second == 0 {
+                return Err(division_by_zero());
+              }
+              // only i64::MIN % -1 wraps, and its remainder is 0 either way
+              let remainder = first.wrapping_rem(second);
+              Value::Literal(Literal::Int(if remainder != 0 && (remainder < 0) != (second < 0) {
+                remainder + second
+              } else {
+                remainder
+              }))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &POW) => {
+              if second < 0 {
+                Value::Literal(Literal::Num((first as f64).powf(second as f64)))
+              } else {
+                let exp = if second > u32::MAX as i64 { u32::MAX } else { second as u32 };
+                Value::Literal(Literal::Int(first.checked_pow(exp).ok_or_else(overflow)?))
+              }
+            },
+            (&Literal::Int(first), &Literal::Int(second), &BIT_AND) => {
+              Value::Literal(Literal::Int(first & second))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &BIT_OR) => {
+              Value::Literal(Literal::Int(first | second))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &BIT_XOR) => {
+              Value::Literal(Literal::Int(first ^ second))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &SHL) => {
+              if second < 0 {
+                return Err(overflow());
+              }
+              let res = if second < 64 { first << second } else { 0 };
+              if second >= 64 && first != 0 || second < 64 && res >> second != first {
+                return Err(overflow());
+              }
+              Value::Literal(Literal::Int(res))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &SHR) => {
+              if second < 0 {
+                return Err(overflow());
+              }
+              Value::Literal(Literal::Int(first >> second.min(63)))
+            },
+
+            (&Literal::Int(first), &Literal::Int(second), &LT) => {
+              Value::Literal(Literal::Bool(first < second))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &GT) => {
+              Value::Literal(Literal::Bool(first > second))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &LTOREQ) => {
+              Value::Literal(Literal::Bool(first <= second))
+            },
+            (&Literal::Int(first), &Literal::Int(second), &GTOREQ) => {
+              Value::Literal(Literal::Bool(first >= second))
+            },
+
             // NUMBER OPERATIONS
             (&Literal::Num(first), &Literal::Num(second), &ADD) => {
               Value::Literal(Literal::Num(first + second))
@@ -437,6 +1041,15 @@ impl VMExec {
             (&Literal::Num(first), &Literal::Num(second), &DIVIDE) => {
               Value::Literal(Literal::Num(first / second))
             },
+            (&Literal::Num(first), &Literal::Num(second), &INT_DIVIDE) => {
+              Value::Literal(Literal::Num((first / second).floor()))
+            },
+            (&Literal::Num(first), &Literal::Num(second), &MOD) => {
+              Value::Literal(Literal::Num(first - second * (first / second).floor()))
+            },
+            (&Literal::Num(first), &Literal::Num(second), &POW) => {
+              Value::Literal(Literal::Num(first.powf(second)))
+            },
 
             // BOOLEAN OPERATIONS
             (&Literal::Num(first), &Literal::Num(second), &LT) => {
@@ -449,17 +1062,44 @@ impl VMExec {
               Value::Literal(Literal::Bool(first <= second))
             },
             (&Literal::Num(first), &Literal::Num(second), &GTOREQ) => {
-              Value::Literal(Literal::Bool(first <= second))
+              Value::Literal(Literal::Bool(first >= second))
+            },
+
+            // RANGE OPERATIONS
+            (&Literal::Int(first), &Literal::Int(second), &RANGE) => {
+              Value::Literal(Literal::Range(first, second))
+            },
+            (&Literal::Num(first), &Literal::Num(second), &RANGE) if first.fract() == 0.0 && second.fract() == 0.0 => {
+              Value::Literal(Literal::Range(first as i64, second as i64))
+            },
+
+            // EQUALITY OPERATIONS
+            (_, _, &EQ) => {
+              Value::Literal(Literal::Bool(literal_eq(lit1, lit2)))
+            },
+            (_, _, &NEQ) => {
+              Value::Literal(Literal::Bool(!literal_eq(lit1, lit2)))
             },
 
             // STRING OPERATIONS
             (&Literal::String(ref first), &Literal::Num(second), &MULTIPLY) |
             (&Literal::Num(second), &Literal::String(ref first), &MULTIPLY) => {
-              Value::Literal(Literal::String(format!{"{}", first}.repeat(second as usize)))
+              // saturates, so inf is too long and NaN repeats nothing
+              Value::Literal(Literal::String(repeat_string(first, second as i64, get_pos())?))
+            },
+            (&Literal::String(ref first), &Literal::Int(second), &MULTIPLY) |
+            (&Literal::Int(second), &Literal::String(ref first), &MULTIPLY) => {
+              Value::Literal(Literal::String(repeat_string(first, second, get_pos())?))
             },
             (&Literal::String(ref first), &Literal::Num(second), &ADD) => {
               Value::Literal(Literal::String(format!("{}{}", first, second)))
             },
+            (&Literal::String(ref first), &Literal::Int(second), &ADD) => {
+              Value::Literal(Literal::String(format!("{}{}", first, second)))
+            },
+            (&Literal::Int(first), &Literal::String(ref second), &ADD) => {
+              Value::Literal(Literal::String(format!("{}{}", first, second)))
+            },
             (&Literal::String(ref first), &Literal::String(ref second), &ADD) => {
               Value::Literal(Literal::String(format!("{}{}", first, second)))
             },
@@ -475,6 +1115,13 @@ impl VMExec {
           self.root.pool.push(res);
           Ok(res_point)
         },
+        (first, second) if *operation == EQ || *operation == NEQ => {
+          let eq = value_eq(first, second);
+          let res = Box::new(Value::Literal(Literal::Bool(if *operation == EQ {eq} else {!eq})));
+          let res_point: *const Value = &*res;
+          self.root.pool.push(res);
+          Ok(res_point)
+        },
         (&Value::Variable(ref identifier, ref pos), _) => {
           match operation {
             &ASSIGN => {
@@ -505,22 +1152,13 @@ impl VMExec {
             }
           }
         },
-        (&Value::Pointer(ref identifier, ref pos, scope), _) => {
+        (&Value::Pointer(ref identifier, pos, scope), _) => {
           match operation {
             &ASSIGN => {
               let mut scope = unsafe {
                 &mut *scope
               };
-              if true { // variable strict mode
-                match scope.get_var(identifier) {
-                  Some(_) => {},
-                  None => return Err(VMExecError::VariableNotDefined(identifier.to_string(), match pos {
-                    &Some(pos) => pos,
-                    &None => 0
-                  }))
-                }
-              }
-              scope.set_var(identifier, val2);
+              scope.set_field(identifier, val2, pos)?;
               Ok(val2)
             },
             &LET => {
@@ -540,143 +1178,129 @@ impl VMExec {
     }
   }
 
-  fn print_stack(&self) {
-    print!("stack: {}\n---------\n", self.stacki);
-    for (i, v) in self.stack.iter().enumerate() {
-      unsafe {
-        if *v != &Value::None {
-          print!("    {}: {:?}\n", i, **v);
-        }
-      }
+  fn do_exec(&mut self, program: Program, append: bool) -> Result<String, VMExecError> {
+    self.reset(append);
+
+    let Program { module, mut operations } = program;
+
+    if module.is_debug {
+      self.is_debug = true;
     }
-    print!("---------\n");
-  }
 
-  fn do_exec(&mut self, mut program: Program, append: bool) -> Result<String, VMExecError> {
-    self.reset(append);
+    // the code is the next source of the session, its debug positions follow the spans already loaded
+    let file = self.sources.len() as u32;
+    self.spans.extend(module.spans.iter().map(|span| Span { file, ..*span }));
+    if module.source.is_some() {
+      self.contains_code = true;
+    }
+    self.sources.push(Source {
+      name: module.file,
+      code: module.source.unwrap_or_default()
+    });
 
     if append {
-      self.program.append(&mut program);
+      // continue with the appended code, not at the end of the previous one
+      self.op_i = self.program.len() as i32;
+      self.program.append(&mut operations);
     } else {
-      self.program = program;
+      self.program = operations;
     }
 
-    let mut meta_end = false;
     let self_point: *mut Self = self;
 
     loop {
-      let op: &Operation = unsafe {
-        &((*self_point).program[self.op_i as usize])
+      let op: &Operation = match unsafe { (&(*self_point).program).get(self.op_i as usize) } {
+        Some(op) => op,
+        None => return Err(VMExecError::ProgramOutOfBounds(self.op_i))
       };
       let code: &Option<OPCode> = &op.code;
       let content = &op.content;
 
       if let &Some(ref code) = code {
-        if !meta_end {
-          match *code {
-            META_END => meta_end = true,
-            DEBUG => self.is_debug = true,
-            DEBUG_CODE => {
-              self.contains_code = true;
-              let mut query: Vec<u8> = Vec::new();
-              loop {
-                self.op_i += 1;
-                let op: &Operation = unsafe {
-                  &((*self_point).program[self.op_i as usize])
-                };
-                let code: &Option<OPCode> = &op.code;
-                if let &Some(ref code) = code {
-                  if code == &DEBUG_CODE_END {
-                    break;
-                  }
-                }
-                
-                query.push(op.val);
-              }
-
-              let query = match str::from_utf8(&query) {
-                Ok(v) => v,
-                Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-              };
-
-              if append {
-                self.query += query;
-              } else {
-                self.query = query.to_string();
-              }
-            },
-            _ => {}
-          }
-          
-          self.op_i += 1;
-          continue;
-        }
-
         {
         //   let cont = format!("{:#?}", code);
         //   let mut repeat: i32 = 14 - cont.len() as i32;
         //   repeat = if repeat < 0 {0} else {repeat};
         //   println!("code: {}{} | {:?}", cont, " ".repeat(repeat as usize), content);
-          // self.print_stack();
         }
         
+        if self.root.should_gc() {
+          self.gc();
+        }
+
         match *code {
           END => {
             unsafe {
               return Ok(value_to_string((*self_point).scope_stack_peek()?, self.stack_pop(), true)?);
             }
           },
-          PUSH_NUM => {
-            let val = Box::new(Value::Literal(Literal::Num(match content {
-              &OperationLiteral::Num(num) => num,
+          PUSH_CONST => {
+            let val = Box::new(Value::Literal(match content {
+              &OperationLiteral::Num(num) => Literal::Num(num),
+              &OperationLiteral::Int64(int) => Literal::Int(int),
+              &OperationLiteral::String(ref s, _) => Literal::String(s.to_owned()),
               _ => return Err(VMExecError::InvalidOperationContent(self.op_i as usize))
-            })));
-            self.op_i += 8; // offset of f64
+            }));
+            self.op_i += 4; // offset of the constant index
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
           },
-          PUSH_INT => {
-            let val = Box::new(Value::Literal(Literal::Int(match content {
+          PUSH_JUMP => {
+            let val = match content {
               &OperationLiteral::Int(int) => int,
               _ => return Err(VMExecError::InvalidOperationContent(self.op_i as usize))
-            })));
+            };
             self.op_i += 4; // offset of i32
-            let val_point = &*val as *const Value;
-            self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.jump_stack_push(val)?;
           },
-          PUSH_JUMP => {
+          PUSH_LOOP => {
             let val = match content {
               &OperationLiteral::Int(int) => int,
               _ => return Err(VMExecError::InvalidOperationContent(self.op_i as usize))
             };
             self.op_i += 4; // offset of i32
-            self.jump_stack_push(val);
+
+            let continue_pos = self.op_i;
+            self.jump_stack_push(continue_pos)?;
+            self.jump_stack_push(continue_pos + val)?;
+          },
+          POP_LOOP => {
+            self.jump_stack_pop(POP_LOOP, None)?;
+            self.jump_stack_pop(POP_LOOP, None)?;
+          },
+          BREAK => {
+            let drop = self.get_int()?;
+
+            let val = self.stack_pop();
+            for _ in 0..drop {
+              self.stack_pop();
+            }
+            self.stack_push(val)?;
+
+            self.op_i = self.jump_stack_pop(BREAK, None)?;
+            self.jump_stack_pop(BREAK, None)?;
+          },
+          CONTINUE => {
+            let pos = self.get_debug_pos()?;
+
+            let len = self.jump_stack.len();
+            if len < 2 {
+              return Err(VMExecError::ContinueWithoutLoop(pos));
+            }
+            self.op_i = self.jump_stack[len - 2];
           },
           PUSH_BOOL => {
             let b = self.consume();
             let val = Box::new(Value::Literal(Literal::Bool(if b >= 1 {true} else {false})));
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
-          },
-          PUSH_STRING => {
-            let val = Box::new(Value::Literal(Literal::String(match content {
-              &OperationLiteral::String(ref s, len) => {
-                self.op_i += len as i32; // offset of string
-                s.to_owned()
-              },
-              _ => return Err(VMExecError::InvalidOperationContent(self.op_i as usize))
-            })));
-            let val_point = &*val as *const Value;
-            self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
           },
           PUSH_VAR => {
             let identifier = match content {
               &OperationLiteral::String(ref s, len) => {
-                self.op_i += len as i32; // offset of string
+                self.op_i += len as i32; // offset of the symbol index
                 s.to_owned()
               },
               _ => return Err(VMExecError::InvalidOperationContent(self.op_i as usize))
@@ -687,7 +1311,7 @@ impl VMExec {
             let val = Box::new(Value::Variable(identifier.to_string(), pos)); // temp
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
           },
           PUSH_POINTER => {
             let lookup = self.get_string()?;
@@ -696,7 +1320,7 @@ impl VMExec {
             let val = Box::new(Value::Pointer(lookup.to_string(), pos, self.scope_stack_peek()?)); // temp
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
           },
           PUSH_VALUE | PUSH_VALUE_DIRECT => {
             let value = self.stack_pop();
@@ -714,27 +1338,17 @@ impl VMExec {
                 };
 
                 match val {
-                  Some(val) => self.stack_push(val),
-                  None => self.stack_push(NIL)
+                  Some(val) => self.stack_push(val)?,
+                  None => self.stack_push(NIL)?
                 }
               },
-              &Value::Pointer(ref identifier, _, scope) => {
-                let mut scope = unsafe {
-                  &mut *scope
+              &Value::Pointer(ref identifier, pos, scope) => {
+                let scope = unsafe {
+                  &*scope
                 };
-
-                let val = if *code == PUSH_VALUE_DIRECT {
-                  scope.get_var_directly(identifier)
-                } else {
-                  scope.get_var(identifier)
-                };
-
-                match val {
-                  Some(val) => self.stack_push(val),
-                  None => self.stack_push(NIL)
-                }
+                self.stack_push(scope.get_field(identifier, pos)?)?;
               }
-              _ => self.stack_push(value)
+              _ => self.stack_push(value)?
             }
           },
           PUSH_FUNC => {
@@ -742,15 +1356,23 @@ impl VMExec {
             let body_offset = self.get_int()?;
             let par_len = self.get_int()?;
 
-            let mut parameters = Vec::with_capacity(par_len as usize);
+            let mut parameters = Vec::new();
             for i in 0..par_len {
               parameters.push(self.get_string()?);
             }
 
-            let val = Box::new(Value::Literal(Literal::Function(Function::InCode(self.op_i + body_offset, parameters))));
+            let scope = self.scope_stack_peek()?;
+            let val = Box::new(Value::Literal(Literal::Function(Function::InCode(self.op_i + body_offset, parameters, scope))));
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
+          },
+          CALL_FUNC_STACK_ARGS if self.stack.len() >= 2 && matches!(unsafe { &*self.stack[self.stack.len() - 2] }, &Value::Literal(Literal::Nil)) => {
+            // the struct is built as the base of another one, its __init__ is only called through `super`
+            self.get_debug_pos()?;
+            self.stack_pop();
+            self.stack_pop();
+            self.stack_push(NIL)?;
           },
           CALL_FUNC | CALL_FUNC_STACK_ARGS => {
             let self_point = self as *mut Self;
@@ -758,25 +1380,39 @@ impl VMExec {
             let pos = self.get_debug_pos()?;
 
             let func_val = self.stack_pop();
+            let name = call_name(func_val);
             let func = unsafe {
               (*self_point).cast_func(func_val, pos)?
             };
 
             let args_len = if *code == CALL_FUNC {
-              self.get_int()? as usize
+              self.get_int()? as i64
             } else {
               let val = self.stack_pop();
-              self.cast_int(val, pos)? as usize
+              self.cast_int(val, pos)?
+            };
+            let args_len = self.stack_count(args_len, pos)?;
+
+            // arguments are resolved where the call is made, the function scope doesn't see the caller.
+            // CALL_FUNC_STACK_ARGS runs in the instance scope of a struct, the caller is the scope below it
+            let caller_scope = if *code == CALL_FUNC {
+              self.scope_stack_peek()?
+            } else {
+              match self.scope_stacki.checked_sub(1) {
+                Some(i) => self.scope_stack[i],
+                None => return Err(VMExecError::ScopeOutOfBounds(CALL_FUNC_STACK_ARGS, self.scope_stacki, self.scope_stack.len()))
+              }
             };
 
-            let mut args = Vec::with_capacity(args_len as usize);
+            let mut args = Vec::with_capacity(args_len);
             for _ in 0..args_len {
-              args.push(self.stack_pop());
+              let arg = self.stack_pop();
+              args.push(self.get_value_in(arg, caller_scope, pos)?);
             }
             args = args.into_iter().rev().collect();
 
             match func {
-              FunctionType::InCode(to, func_pars) => {
+              FunctionType::InCode(to, func_pars, parent, instance) => {
                 let func_pars_len = func_pars.len();
 
                 if true { // strict function mode
@@ -792,6 +1428,13 @@ impl VMExec {
                   }
                 }
 
+                if *code == CALL_FUNC {
+                  let mut scope = Box::new(Scope::new(&mut *self.root as *mut Root, Some(parent)));
+                  let scope_point = &mut *scope as *mut Scope;
+                  self.root.scopes.push(scope);
+                  self.scope_stack_push(scope_point)?;
+                }
+
                 let mut scope = unsafe {
                   &mut *self.scope_stack_peek()?
                 };
@@ -800,21 +1443,50 @@ impl VMExec {
                   scope.set_var_directly(v, args[k]);
                 }
 
+                // CALL_FUNC_STACK_ARGS runs __init__, the instance is the scope it runs in
+                let instance = match instance {
+                  Some(instance) => Some(instance),
+                  None if *code == CALL_FUNC_STACK_ARGS => Some(scope as *mut Scope),
+                  None => None
+                };
+                if let Some(instance) = instance {
+                  let instance = self.root.alloc(Value::Instance(instance));
+                  scope.set_var_directly("self", instance);
+                }
+
                 let jump_stack = self.op_i;
-                self.jump_stack_push(jump_stack);
+                self.jump_stack_push(jump_stack)?;
+
+                self.call_stack.push(CallFrame {
+                  stacki: self.stack.len(),
+                  jump_stacki: self.jump_stack.len(),
+                  scope_stacki: self.scope_stacki,
+                  site: CallSite {
+                    name,
+                    func: to,
+                    pos
+                  }
+                });
 
                 self.op_i = to;
               },
               FunctionType::Native(func) => {
-                let res = func(self.scope_stack_peek()?, args)?;
+                if *code == CALL_FUNC {
+                  let mut scope = Box::new(Scope::new(&mut *self.root as *mut Root, Some(caller_scope)));
+                  let scope_point = &mut *scope as *mut Scope;
+                  self.root.scopes.push(scope);
+                  self.scope_stack_push(scope_point)?;
+                }
+
+                let res = func(self.scope_stack_peek()?, args).map_err(|err| err.at_call(pos))?;
                 match res {
                   Some(val) => {
                     let val = Box::new(val); // temp
                     let val_point = &*val as *const Value;
                     self.root.pool.push(val);
-                    self.stack_push(val_point);
+                    self.stack_push(val_point)?;
                   },
-                  None => self.stack_push(NIL)
+                  None => self.stack_push(NIL)?
                 }
               }
             }
@@ -823,10 +1495,23 @@ impl VMExec {
             let pos = self.get_debug_pos()?;
 
             let s = self.stack_pop();
+            let name = call_name(s);
             let s = self.cast_struct(s, pos)?;
 
             let jump_stack = self.op_i;
-            self.jump_stack_push(jump_stack);
+            self.jump_stack_push(jump_stack)?;
+
+            // ended by the JUMPSTACKABS at the end of the struct body
+            self.call_stack.push(CallFrame {
+              stacki: self.stack.len(),
+              jump_stacki: self.jump_stack.len(),
+              scope_stacki: self.scope_stacki,
+              site: CallSite {
+                name,
+                func: s.0,
+                pos
+              }
+            });
 
             self.op_i = s.0;
           },
@@ -838,7 +1523,7 @@ impl VMExec {
             let val = Box::new(val); // temp
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
 
             // self.stack_push(NIL);
           },
@@ -847,25 +1532,231 @@ impl VMExec {
 
             let scope_val = self.stack_pop();
             let scope = self.cast_instance(scope_val, pos)?;
-            self.scope_stack_push(scope, pos)?;
+            self.scope_stack_push(scope)?;
+          },
+          PUSH_NIL => {
+            self.stack_push(NIL)?;
+          },
+          PUSH_ARRAY => {
+            let pos = self.get_debug_pos()?;
+            let len = self.get_int()? as i64;
+            let len = self.stack_count(len, pos)?;
+
+            let mut elements = Vec::with_capacity(len);
+            for _ in 0..len {
+              let val = self.stack_pop();
+              elements.push(self.get_value(val, pos)?);
+            }
+            elements.reverse();
+
+            let val = Box::new(Value::Array(Rc::new(RefCell::new(elements))));
+            let val_point = &*val as *const Value;
+            self.root.pool.push(val);
+            self.stack_push(val_point)?;
+          },
+          PUSH_MAP => {
+            let pos = self.get_debug_pos()?;
+            let len = self.get_int()? as i64;
+            let len = self.stack_count(len * 2, pos)? / 2; // a key and a value each
+
+            let mut entries = BTreeMap::new();
+            let mut pairs = Vec::with_capacity(len);
+            for _ in 0..len {
+              let val = self.stack_pop();
+              let val = self.get_value(val, pos)?;
+              let key = self.stack_pop();
+              pairs.push((self.get_key(key, pos)?, val));
+            }
+            // popped in reverse, later duplicates win
+            for (key, val) in pairs.into_iter().rev() {
+              entries.insert(key, val);
+            }
+
+            let val = Box::new(Value::Map(Rc::new(RefCell::new(entries))));
+            let val_point = &*val as *const Value;
+            self.root.pool.push(val);
+            self.stack_push(val_point)?;
+          },
+          DECLARE => {
+            let identifier = self.get_string()?;
+
+            let val = self.stack_pop();
+            let val = self.get_value(val, None)?;
 
             let scope = unsafe {
-              &mut *scope
+              &mut *self.scope_stack_peek()?
             };
+            scope.set_var_directly(&identifier, val);
+          },
+          ITER | ITER_RESULT => {
+            let pos = self.get_debug_pos()?;
+            let skip = if *code == ITER { self.get_int()? } else { 0 };
 
-            scope.set_var_directly("self", scope_val);
+            let val = self.stack_pop();
+            let val = self.get_value(val, pos)?;
+
+            let state = match unsafe { &*val } {
+              &Value::Literal(Literal::Range(start, end)) => Some(IterState::Range(start, end)),
+              &Value::Literal(Literal::String(ref s)) => Some(IterState::Chars(s.chars().collect(), 0)),
+              &Value::Array(ref elements) => Some(IterState::Array(elements.clone(), 0)),
+              &Value::Map(ref entries) => Some(IterState::Keys(entries.borrow().keys().cloned().collect(), 0)),
+              &Value::Instance(scope) => {
+                let scope = unsafe { &mut *scope };
+
+                match (scope.find_field("__iter__"), scope.find_field("__next__")) {
+                  (Some(func), _) if *code == ITER => {
+                    // continue into the call, ITER_RESULT picks up what it returns
+                    self.stack_push(scope.bind(func))?;
+                    None
+                  },
+                  (_, Some(_)) => Some(IterState::Instance(val)),
+                  _ => return Err(VMExecError::InvalidCast(unsafe { (*val).clone() }, "<iterable>".to_string(), pos))
+                }
+              },
+              val => return Err(VMExecError::InvalidCast(val.clone(), "<iterable>".to_string(), pos))
+            };
+
+            if let Some(state) = state {
+              let val = Box::new(Value::Iterator(RefCell::new(state)));
+              let val_point = &*val as *const Value;
+              self.root.pool.push(val);
+              self.stack_push(val_point)?;
+
+              self.op_i += skip;
+            }
           },
-          PUSH_NIL => {
-            self.stack_push(NIL);
+          ITER_NEXT => {
+            let pos = self.get_debug_pos()?;
+            let skip = self.get_int()?;
+            let exit = self.get_int()?;
+
+            let len = self.stack.len();
+            if len < 2 {
+              return Err(VMExecError::StackUnderflow(2, len, pos));
+            }
+
+            let iterator = self.stack[len - 2];
+            let state = match unsafe { &*iterator } {
+              &Value::Iterator(ref state) => state,
+              val => return Err(VMExecError::NotAnIterator(val.clone(), pos))
+            };
+
+            let instance = match *state.borrow() {
+              IterState::Instance(val) => Some(val),
+              _ => None
+            };
+
+            match instance {
+              Some(val) => {
+                let scope = self.cast_instance(val, pos)?;
+                let scope = unsafe { &mut *scope };
+
+                let func = match scope.find_field("__next__") {
+                  Some(func) => func,
+                  None => return Err(VMExecError::VariableNotDefined("__next__".to_string(), match pos {
+                    Some(pos) => pos,
+                    None => 0
+                  }))
+                };
+
+                // continue into the call, ITER_CHECK looks at what it returns
+                self.stack_push(scope.bind(func))?;
+              },
+              None => match self.iter_next(state) {
+                Some(val) => {
+                  self.stack_push(val)?;
+                  self.op_i += skip;
+                },
+                None => self.op_i += exit
+              }
+            }
+          },
+          ITER_CHECK => {
+            let pos = self.get_debug_pos()?;
+            let exit = self.get_int()?;
+
+            let val = self.stack_peek();
+            if let &Value::Literal(Literal::Nil) = unsafe { &*self.get_value(val, pos)? } {
+              self.stack_pop();
+              self.op_i += exit;
+            }
+          },
+          GET_INDEX => {
+            let pos = self.get_debug_pos()?;
+
+            let index = self.stack_pop();
+            let collection = self.stack_pop();
+            let collection = self.get_value(collection, pos)?;
+
+            match unsafe { &*collection } {
+              &Value::Array(ref elements) => {
+                let elements = elements.borrow();
+                let index = self.get_index(index, elements.len(), pos)?;
+                self.stack_push(elements[index])?;
+              },
+              &Value::Literal(Literal::String(ref s)) => {
+                let len = s.chars().count();
+                let index = self.get_index(index, len, pos)?;
+
+                let val = Box::new(Value::Literal(Literal::String(s.chars().nth(index).unwrap().to_string())));
+                let val_point = &*val as *const Value;
+                self.root.pool.push(val);
+                self.stack_push(val_point)?;
+              },
+              &Value::Map(ref entries) => {
+                let key = self.get_key(index, pos)?;
+                match entries.borrow().get(&key) {
+                  Some(val) => self.stack_push(*val)?,
+                  None => return Err(VMExecError::KeyNotFound(key, pos))
+                }
+              },
+              val => return Err(VMExecError::InvalidCast(val.clone(), "<array>".to_string(), pos))
+            }
           },
-          ADD | SUB | MULTIPLY | ASSIGN | LET | DIVIDE |
-          GT | LT | GTOREQ | LTOREQ => {
+          SET_INDEX => {
+            let pos = self.get_debug_pos()?;
+
+            let val = self.stack_pop();
+            let val = self.get_value(val, pos)?;
+            let index = self.stack_pop();
+            let array = self.stack_pop();
+            let array = self.get_value(array, pos)?;
+
+            match unsafe { &*array } {
+              &Value::Array(ref elements) => {
+                let len = elements.borrow().len();
+                let index = self.get_index(index, len, pos)?;
+                elements.borrow_mut()[index] = val;
+              },
+              &Value::Map(ref entries) => {
+                let key = self.get_key(index, pos)?;
+                entries.borrow_mut().insert(key, val);
+              },
+              array => return Err(VMExecError::InvalidCast(array.clone(), "<array>".to_string(), pos))
+            }
+
+            self.stack_push(val)?;
+          },
+          ADD | SUB | MULTIPLY | ASSIGN | LET | DIVIDE | INT_DIVIDE | MOD |
+          GT | LT | GTOREQ | LTOREQ | EQ | NEQ | RANGE |
+          POW | BIT_AND | BIT_OR | BIT_XOR | SHL | SHR => {
             let pos = self.get_debug_pos()?;
 
             let second = self.stack_pop();
             let first = self.stack_pop();
             let res = self.literal_operation(first, second, code, pos)?;
-            self.stack_push(res);
+            self.stack_push(res)?;
+          },
+          NOT => {
+            let pos = self.get_debug_pos()?;
+
+            let val = self.stack_pop();
+            let b = self.cast_bool(val, pos)?;
+
+            let val = Box::new(Value::Literal(Literal::Bool(!b)));
+            let val_point = &*val as *const Value;
+            self.root.pool.push(val);
+            self.stack_push(val_point)?;
           },
           // DOT => {
           //   let pos = self.get_debug_pos()?;
@@ -875,19 +1766,16 @@ impl VMExec {
 
 
           // },
-          SCOPE_NEW | SCOPE_NEW_FUNC => {
-            let parent = match unsafe { &*self.stack_peek() } {
-              &Value::Pointer(_, _, scope) => scope,
-              _ => self.scope_stack_peek()?
-            };
+          SCOPE_NEW => {
+            let parent = self.scope_stack_peek()?;
 
-            let mut scope = Box::new(Scope::new(&mut self.root as *mut Root, Some(parent)));
+            let mut scope = Box::new(Scope::new(&mut *self.root as *mut Root, Some(parent)));
             let mut scope_point = &mut *scope as *mut Scope;
             self.root.scopes.push(scope);
-            self.scope_stack_push(scope_point, None)?;
+            self.scope_stack_push(scope_point)?;
 
 
-            // let mut scope = Box::new(Scope::new(&mut self.root as *mut Root, Some(self.scope_stack_peek()?)));
+            // let mut scope = Box::new(Scope::new(&mut *self.root as *mut Root, Some(self.scope_stack_peek()?)));
             // let mut scope_point = &mut *scope as *mut Scope;
             // self.root.scopes.push(scope);
             // self.scope_stack_push(scope_point, None)?;
@@ -896,18 +1784,66 @@ impl VMExec {
             self.scope_stack_pop()?;
           },
           SCOPE_FORWARD => {
+            if self.scope_stacki + 1 >= self.scope_stack.len() {
+              return Err(VMExecError::ScopeOutOfBounds(SCOPE_FORWARD, self.scope_stacki, self.scope_stack.len()));
+            }
             self.scope_stacki += 1;
           },
           SCOPE_BACK => {
+            if self.scope_stacki == 0 {
+              return Err(VMExecError::ScopeOutOfBounds(SCOPE_BACK, self.scope_stacki, self.scope_stack.len()));
+            }
             self.scope_stacki -= 1;
           },
+          EXTEND => {
+            let pos = self.get_debug_pos()?;
+
+            let base_val = self.stack_pop();
+            let base = self.cast_instance(base_val, pos)?;
+
+            let scope_point = self.scope_stack_peek()?;
+            let scope = unsafe {
+              &mut *scope_point
+            };
+            scope.base = Some(base);
+            scope.set_var_directly("super", base_val);
+
+            // the base and its own bases are now parts of this instance
+            let mut part = Some(base);
+            while let Some(part_point) = part {
+              let part_scope = unsafe {
+                &mut *part_point
+              };
+              part_scope.owner = Some(scope_point);
+              part = part_scope.base;
+            }
+          },
           SCOPE_PUSH => {
             let scope = self.scope_stack_pop()?;
             let val = Value::Instance(scope);
             let val = Box::new(val);
             let val_point = &*val as *const Value;
             self.root.pool.push(val);
-            self.stack_push(val_point);
+            self.stack_push(val_point)?;
+          },
+          RETURN => {
+            let pos = self.get_debug_pos()?;
+
+            let val = self.stack_pop();
+            let val = self.get_value(val, pos)?;
+
+            let frame = match self.call_stack.pop() {
+              Some(frame) => frame,
+              None => return Err(VMExecError::ReturnWithoutCall(pos))
+            };
+
+            // unwind everything the function left behind, SCOPE_END at the call site ends the function scope
+            self.stack.truncate(frame.stacki);
+            self.jump_stack.truncate(frame.jump_stacki);
+            self.scope_stacki = frame.scope_stacki;
+
+            self.stack_push(val)?;
+            self.op_i = self.jump_stack_pop(RETURN, pos)?;
           },
           JUMP => {
             let to = self.get_int()?;
@@ -916,11 +1852,20 @@ impl VMExec {
             self.op_i += to;
           },
           JUMPSTACK => {
-            let to = self.jump_stack_pop()?;
+            let to = self.jump_stack_pop(JUMPSTACK, None)?;
             self.op_i += to;
           },
           JUMPSTACKABS => {
-            let to = self.jump_stack_pop()?;
+            // jumping back from a struct body ends the frame of its CALL_STRUCT
+            let ends_frame = match self.call_stack.last() {
+              Some(frame) => frame.jump_stacki == self.jump_stack.len(),
+              None => false
+            };
+            if ends_frame {
+              self.call_stack.pop();
+            }
+
+            let to = self.jump_stack_pop(JUMPSTACKABS, None)?;
             self.op_i = to;
           },
           JUMPIFN => {
@@ -936,6 +1881,16 @@ impl VMExec {
               self.op_i += to;
             }
           },
+          DUP => {
+            let count = self.get_int()? as i64;
+            let count = self.stack_count(count, None)?;
+
+            let start = self.stack.len() - count;
+            for i in start..start + count {
+              let val = self.stack[i];
+              self.stack_push(val)?;
+            }
+          },
           POP => {
             let val = self.stack_pop();
 
@@ -958,7 +1913,6 @@ impl VMExec {
             }
           }
           _ => {
-            self.print_stack();
             return Err(VMExecError::UnsupportedOPCode(format!("{:?}", op)));
           }
         };
@@ -971,9 +1925,14 @@ impl VMExec {
   }
 
   pub fn exec(&mut self, program: Program, append: bool) -> Result<String, VMExecError> {
+    if let Err(err) = verify(&program) {
+      return Err(VMExecError::VerifyErr(err));
+    }
+
     match self.do_exec(program, append) {
       Ok(val) => Ok(val),
       Err(err) => {
+        self.trace = self.call_trace();
         self.op_i = self.program.len() as i32;
         Err(err)
       }
@@ -0,0 +1,356 @@
+//! Reading and writing of `.lby` files.
+//!
+//! ```text
+//! magic     4b  "LBY\0"
+//! version   1b  BYTECODE_VERSION
+//! flags     1b  FLAG_DEBUG
+//! count     1b  number of sections
+//! table     count * [kind: 1b, length: 4b]
+//! sections  the content of every section, in the order of the table
+//! checksum  4b  FNV-1a of everything before it
+//! ```
+//!
+//! All integers are little-endian. Unknown section kinds are skipped.
+
+use std::str;
+
+use span::Span;
+
+pub const MAGIC: [u8; 4] = [b'L', b'B', b'Y', 0x00];
+
+/// Programs built with another version are rejected.
+/// 3: header and section table, instead of header opcodes
+/// 4: literals and names as indices into the constant pool and symbol table
+/// 5: debug positions as indices into the span table
+/// 6: debug position of CONTINUE
+pub const BYTECODE_VERSION: u8 = 0x06;
+
+pub const FLAG_DEBUG: u8 = 0x01;
+
+// section kinds
+pub const SECTION_CODE: u8 = 0x01;
+pub const SECTION_CONSTANTS: u8 = 0x02;
+pub const SECTION_SOURCE: u8 = 0x03;
+pub const SECTION_LINES: u8 = 0x04;
+pub const SECTION_SYMBOLS: u8 = 0x05;
+pub const SECTION_SPANS: u8 = 0x06;
+
+// constant kinds
+const CONSTANT_NUM: u8 = 0x01;
+const CONSTANT_INT: u8 = 0x02;
+const CONSTANT_STRING: u8 = 0x03;
+
+#[derive(Debug)]
+pub enum FormatError {
+  InvalidMagic,
+  // found version, supported version
+  VersionMismatch(u8, u8),
+  // expected checksum, actual checksum
+  ChecksumMismatch(u32, u32),
+  // position in file
+  Truncated(usize),
+  // section kind
+  DuplicateSection(u8),
+  MissingSection(u8),
+  InvalidSection(u8)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+  Num(f64),
+  Int(i64),
+  String(String)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Module {
+  pub is_debug: bool,
+  pub code: Vec<u8>,
+  pub constants: Vec<Constant>,
+  pub source: Option<String>,
+  // code offset, source line (1-indexed), for the first instruction of every line
+  pub lines: Vec<(u32, u32)>,
+  pub symbols: Vec<String>,
+  // name of the source file and the spans debug positions refer to, only in debug builds
+  pub file: String,
+  pub spans: Vec<Span>
+}
+
+impl Module {
+  /// The source line the instruction at `offset` was built from.
+  pub fn line_at(&self, offset: usize) -> Option<u32> {
+    match self.lines.binary_search_by_key(&(offset as u32), |&(offset, _)| offset) {
+      Ok(i) => Some(self.lines[i].1),
+      Err(0) => None,
+      Err(i) => Some(self.lines[i - 1].1)
+    }
+  }
+}
+
+pub fn checksum(bytes: &[u8]) -> u32 {
+  let mut hash: u32 = 0x811c9dc5;
+  for &byte in bytes {
+    hash ^= byte as u32;
+    hash = hash.wrapping_mul(0x01000193);
+  }
+  hash
+}
+
+fn push_u32(bytes: &mut Vec<u8>, int: u32) {
+  bytes.extend_from_slice(&int.to_le_bytes());
+}
+
+fn push_str(bytes: &mut Vec<u8>, s: &str) {
+  push_u32(bytes, s.len() as u32);
+  bytes.extend_from_slice(s.as_bytes());
+}
+
+pub fn write(module: &Module) -> Vec<u8> {
+  let mut constants = Vec::new();
+  push_u32(&mut constants, module.constants.len() as u32);
+  for constant in &module.constants {
+    match *constant {
+      Constant::Num(num) => {
+        constants.push(CONSTANT_NUM);
+        constants.extend_from_slice(&num.to_le_bytes());
+      },
+      Constant::Int(int) => {
+        constants.push(CONSTANT_INT);
+        constants.extend_from_slice(&int.to_le_bytes());
+      },
+      Constant::String(ref s) => {
+        constants.push(CONSTANT_STRING);
+        push_str(&mut constants, s);
+      }
+    }
+  }
+
+  let mut symbols = Vec::new();
+  push_u32(&mut symbols, module.symbols.len() as u32);
+  for symbol in &module.symbols {
+    push_str(&mut symbols, symbol);
+  }
+
+  let mut sections = vec![
+    (SECTION_CODE, module.code.clone()),
+    (SECTION_CONSTANTS, constants),
+    (SECTION_SYMBOLS, symbols)
+  ];
+
+  if module.is_debug {
+    let mut spans = Vec::new();
+    push_str(&mut spans, &module.file);
+    push_u32(&mut spans, module.spans.len() as u32);
+    for span in &module.spans {
+      push_u32(&mut spans, span.start);
+      push_u32(&mut spans, span.end);
+      push_u32(&mut spans, span.line);
+      push_u32(&mut spans, span.col);
+    }
+    sections.push((SECTION_SPANS, spans));
+  }
+
+  if let Some(ref source) = module.source {
+    let mut lines = Vec::new();
+    push_u32(&mut lines, module.lines.len() as u32);
+    for &(offset, line) in &module.lines {
+      push_u32(&mut lines, offset);
+      push_u32(&mut lines, line);
+    }
+
+    sections.push((SECTION_SOURCE, source.as_bytes().to_vec()));
+    sections.push((SECTION_LINES, lines));
+  }
+
+  let mut bytes = MAGIC.to_vec();
+  bytes.push(BYTECODE_VERSION);
+  bytes.push(if module.is_debug { FLAG_DEBUG } else { 0x00 });
+  bytes.push(sections.len() as u8);
+
+  for &(kind, ref content) in &sections {
+    bytes.push(kind);
+    push_u32(&mut bytes, content.len() as u32);
+  }
+  for (_, mut content) in sections {
+    bytes.append(&mut content);
+  }
+
+  let sum = checksum(&bytes);
+  push_u32(&mut bytes, sum);
+
+  bytes
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  i: usize
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], FormatError> {
+    if self.i + len > self.bytes.len() {
+      return Err(FormatError::Truncated(self.bytes.len()));
+    }
+    let taken = &self.bytes[self.i..self.i + len];
+    self.i += len;
+    Ok(taken)
+  }
+
+  fn u8(&mut self) -> Result<u8, FormatError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u32(&mut self) -> Result<u32, FormatError> {
+    let mut bv = [0x00; 4];
+    bv.copy_from_slice(self.take(4)?);
+    Ok(u32::from_le_bytes(bv))
+  }
+
+  fn u64(&mut self) -> Result<[u8; 8], FormatError> {
+    let mut bv = [0x00; 8];
+    bv.copy_from_slice(self.take(8)?);
+    Ok(bv)
+  }
+
+  fn string(&mut self) -> Result<String, FormatError> {
+    let len = self.u32()? as usize;
+    match str::from_utf8(self.take(len)?) {
+      Ok(s) => Ok(s.to_string()),
+      Err(_) => Err(FormatError::Truncated(self.i))
+    }
+  }
+}
+
+// reads a whole section with `f`, which has to consume all of it
+fn read_section<T, F>(kind: u8, content: &[u8], f: F) -> Result<T, FormatError>
+  where F: Fn(&mut Reader) -> Result<T, FormatError> {
+  let mut reader = Reader { bytes: content, i: 0 };
+  match f(&mut reader) {
+    Ok(val) if reader.i == content.len() => Ok(val),
+    _ => Err(FormatError::InvalidSection(kind))
+  }
+}
+
+pub fn read(bytes: &[u8]) -> Result<Module, FormatError> {
+  if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+    return Err(FormatError::InvalidMagic);
+  }
+
+  let mut reader = Reader { bytes, i: MAGIC.len() };
+
+  let version = reader.u8()?;
+  if version != BYTECODE_VERSION {
+    return Err(FormatError::VersionMismatch(version, BYTECODE_VERSION));
+  }
+
+  if bytes.len() < reader.i + 4 {
+    return Err(FormatError::Truncated(bytes.len()));
+  }
+  let body_len = bytes.len() - 4;
+  let mut sum_reader = Reader { bytes, i: body_len };
+  let expected = sum_reader.u32()?;
+  let actual = checksum(&bytes[..body_len]);
+  if expected != actual {
+    return Err(FormatError::ChecksumMismatch(expected, actual));
+  }
+  // sections can't reach into the checksum
+  reader.bytes = &bytes[..body_len];
+
+  let flags = reader.u8()?;
+  let count = reader.u8()?;
+
+  let mut table = Vec::with_capacity(count as usize);
+  for _ in 0..count {
+    let kind = reader.u8()?;
+    let len = reader.u32()? as usize;
+    table.push((kind, len));
+  }
+
+  let mut module = Module {
+    is_debug: flags & FLAG_DEBUG != 0,
+    ..Default::default()
+  };
+
+  let mut seen = Vec::new();
+  for (kind, len) in table {
+    let content = reader.take(len)?;
+
+    if seen.contains(&kind) {
+      return Err(FormatError::DuplicateSection(kind));
+    }
+    seen.push(kind);
+
+    match kind {
+      SECTION_CODE => module.code = content.to_vec(),
+      SECTION_CONSTANTS => {
+        module.constants = read_section(kind, content, |reader| {
+          let count = reader.u32()?;
+          let mut constants = Vec::new();
+          for _ in 0..count {
+            constants.push(match reader.u8()? {
+              CONSTANT_NUM => Constant::Num(f64::from_le_bytes(reader.u64()?)),
+              CONSTANT_INT => Constant::Int(i64::from_le_bytes(reader.u64()?)),
+              CONSTANT_STRING => Constant::String(reader.string()?),
+              _ => return Err(FormatError::InvalidSection(kind))
+            });
+          }
+          Ok(constants)
+        })?;
+      },
+      SECTION_SOURCE => {
+        module.source = match str::from_utf8(content) {
+          Ok(source) => Some(source.to_string()),
+          Err(_) => return Err(FormatError::InvalidSection(kind))
+        };
+      },
+      SECTION_LINES => {
+        module.lines = read_section(kind, content, |reader| {
+          let count = reader.u32()?;
+          let mut lines = Vec::new();
+          for _ in 0..count {
+            let offset = reader.u32()?;
+            let line = reader.u32()?;
+            lines.push((offset, line));
+          }
+          Ok(lines)
+        })?;
+      },
+      SECTION_SYMBOLS => {
+        module.symbols = read_section(kind, content, |reader| {
+          let count = reader.u32()?;
+          let mut symbols = Vec::new();
+          for _ in 0..count {
+            symbols.push(reader.string()?);
+          }
+          Ok(symbols)
+        })?;
+      },
+      SECTION_SPANS => {
+        let (file, spans) = read_section(kind, content, |reader| {
+          let file = reader.string()?;
+          let count = reader.u32()?;
+          let mut spans = Vec::new();
+          for _ in 0..count {
+            spans.push(Span {
+              file: 0,
+              start: reader.u32()?,
+              end: reader.u32()?,
+              line: reader.u32()?,
+              col: reader.u32()?
+            });
+          }
+          Ok((file, spans))
+        })?;
+        module.file = file;
+        module.spans = spans;
+      },
+      _ => {}
+    }
+  }
+
+  if !seen.contains(&SECTION_CODE) {
+    return Err(FormatError::MissingSection(SECTION_CODE));
+  }
+
+  Ok(module)
+}
@@ -3,6 +3,8 @@ use self::num::FromPrimitive;
 use std::str;
 
 use parser::Declaration;
+use span::Source;
+use ErrorFormat;
 
 pub use super::lexer;
 pub use lexer::Token;
@@ -11,6 +13,9 @@ pub mod build;
 pub mod exec;
 pub mod cast;
 pub mod native;
+pub mod disasm;
+pub mod verify;
+pub mod format;
 
 use vm::build::VMBuild;
 use vm::exec::{VMExec, Value};
@@ -20,14 +25,14 @@ use self::OPCode::*;
 pub use vm::build::{VMBuildError, VMBuildError::*};
 pub use vm::exec::{VMExecError, VMExecError::*};
 
-use std::mem;
-
-pub type Program = Vec<Operation>;
 pub type Instructions = Vec<u8>;
 pub type Decls = Vec<Box<Declaration>>;
 
 const NIL: *const Value = &Value::Literal(exec::Literal::Nil);
 
+pub use vm::format::BYTECODE_VERSION;
+use vm::format::Constant;
+
 pub fn u(op_code: OPCode) -> u8 {
   op_code as u8
 }
@@ -41,30 +46,29 @@ enum_from_primitive! {
     NULL = 0x00,
     END = 0x01,
 
-    VERSION, // [version: 1b]
-    DEBUG, // []
-
+    // not used, the header is written by vm::format
+    VERSION,
+    DEBUG,
     META_END,
-
     DEBUG_CODE,
     DEBUG_CODE_END,
 
     I32, // [content: 4b],
-    STRING, // [content: str, NULL]
+    STRING, // [index: 4b], a name in the symbol table
 
-    PUSH_NUM, // [content: 8b]
-    PUSH_INT, // [content: 4b]
+    PUSH_NUM, // not used, literals are in the constant pool
+    PUSH_INT, // not used
     PUSH_JUMP, // [content: 4b], position for a JUMPSTACK to use, gets pushed to a separate stack
     PUSH_BOOL,  // [content: 1b]
-    PUSH_STRING, // [content: str, NULL]
-    PUSH_VAR, // [content: str, NULL, I32, debug: 4b], // looks up variable
+    PUSH_STRING, // not used
+    PUSH_VAR, // [index: 4b, I32, debug: 4b], // looks up the variable named by the symbol table entry
     PUSH_VALUE, // [], pops a value, checks if variable, then pushes the value of the variable 
     PUSH_VALUE_DIRECT, // [], pops a value, checks if variable directly, then pushes the value of the variable 
     PUSH_POINTER, // [] // pops a value, pushes a pointer to the value in its scope
     // PUSH_STACK_VAR, // [I32, debug: 4b] //  pops a string from the stack and looks up variable
     PUSH_FUNC, // [I32, debug: 4b, I32, pos: 4b, I32, parameter_len: 4b, STRING, par1: str, STRING, par2: str, ..., body_len: 4b, body]
-    CALL_FUNC, // [I32, debug: 4b, I32, argument_len: 4b],
-    CALL_FUNC_STACK_ARGS, // [I32, debug: 4b] // same as CALL_FUNC, but with the argument length on the stack
+    CALL_FUNC, // [I32, debug: 4b, I32, argument_len: 4b], pushes a new scope for the function
+    CALL_FUNC_STACK_ARGS, // [I32, debug: 4b] // same as CALL_FUNC, but with the argument length on the stack, nil skips the call
     PUSH_STRUCT, // [I32, debug: 4b, I32, pos: 4b],
     CALL_STRUCT,
     GET_SCOPE, // [I32, debug: 4b]
@@ -74,7 +78,7 @@ enum_from_primitive! {
 
     // [content: 0b]
     SCOPE_NEW,
-    SCOPE_NEW_FUNC, // peeks at a function and checks its scope
+    SCOPE_NEW_FUNC, // not used, CALL_FUNC creates the function scope from the scope the function was defined in
     SCOPE_END,
     SCOPE_FORWARD, // moves scope_stacki += 1
     SCOPE_BACK, // moves scope_stacki -= 1
@@ -99,6 +103,48 @@ enum_from_primitive! {
     GT,
     LTOREQ,
     GTOREQ,
+    EQ,
+    NEQ,
+
+    NOT, // [I32, debug: 4b], unary, pops one value
+
+    RETURN, // [I32, debug: 4b], unwinds the current call frame and jumps back to the caller with the top value
+
+    PUSH_LOOP, // [content: 4b], pushes the continue position (after this) and the break position (relative) to the jump stack
+    POP_LOOP, // [], pops the positions pushed by PUSH_LOOP
+    BREAK, // [I32, drop: 4b], keeps the top value, drops `drop` values below it, pops the loop and jumps to its break position
+    CONTINUE, // [I32, debug: 4b], jumps to the continue position of the current loop
+
+    PUSH_ARRAY, // [I32, debug: 4b, I32, len: 4b], pops len values into a new array
+    GET_INDEX, // [I32, debug: 4b], pops the index or key and the collection, pushes the element
+    SET_INDEX, // [I32, debug: 4b], pops the value, the index or key and the collection, pushes the value
+    PUSH_MAP, // [I32, debug: 4b, I32, len: 4b], pops len key and value pairs into a new map
+
+    RANGE, // [I32, debug: 4b], pops end and start, pushes the range start..end
+    DECLARE, // [STRING, name: str, NULL], pops a value and declares it in the current scope
+    ITER, // [I32, debug: 4b, I32, skip: 4b], pops an iterable and pushes an iterator over it, then jumps over the __iter__ call that follows.
+          // for instances with __iter__, pushes the method and continues into the call instead
+    ITER_RESULT, // [I32, debug: 4b], pops the result of __iter__ and pushes an iterator over it
+    ITER_NEXT, // [I32, debug: 4b, I32, skip: 4b, I32, exit: 4b], pushes the next value of the iterator below the top value and jumps over the __next__ call that follows,
+               // jumps to exit when exhausted. for instances, pushes __next__ and continues into the call instead
+    ITER_CHECK, // [I32, debug: 4b, I32, exit: 4b], if the top value (result of __next__) is nil, pops it and jumps to exit
+
+    // operation on top two stack values. [I32, debug: 4b]
+    INT_DIVIDE, // rounds towards negative infinity
+    MOD, // sign of the divisor, like INT_DIVIDE
+
+    PUSH_CONST, // [index: 4b], pushes the entry of the constant pool
+
+    // operation on top two stack values. [I32, debug: 4b]
+    POW, // an int to a negative power is a float
+    BIT_AND, // ints only
+    BIT_OR,
+    BIT_XOR,
+    SHL, // errors on bits shifted out, like an overflowing multiplication
+    SHR, // arithmetic, keeps the sign
+
+    DUP, // [I32, count: 4b], pushes the top count values again, in the same order
+    EXTEND, // [I32, debug: 4b], pops an instance and makes it the base of the current scope, declared as `super`
   }
 }
 
@@ -124,6 +170,7 @@ pub enum OperationLiteral {
   Num(f64),
   String(String, usize),
   Int(i32),
+  Int64(i64),
 
   None
 }
@@ -135,7 +182,39 @@ pub struct Operation {
   content: OperationLiteral
 }
 
-pub fn get_program(bytes: Vec<u8>) -> Program {
+/// The code of a module decoded into one operation per byte, along with the rest of the module.
+#[derive(Debug)]
+pub struct Program {
+  pub module: format::Module,
+  pub operations: Vec<Operation>
+}
+
+pub fn get_program(bytes: Vec<u8>) -> Result<Program, VMExecError> {
+  let module = match format::read(&bytes) {
+    Ok(module) => module,
+    Err(err) => return Err(FormatErr(err))
+  };
+
+  Ok(Program {
+    operations: get_operations(&module),
+    module
+  })
+}
+
+// the 4 bytes following position i
+fn get_i32(bytes: &[u8], i: usize) -> Option<i32> {
+  if i + 4 < bytes.len() {
+    let mut content_vec: [u8; 4] = [0x00; 4];
+    content_vec.copy_from_slice(&bytes[i + 1..i + 5]);
+    Some(i32::from_le_bytes(content_vec))
+  } else {
+    None
+  }
+}
+
+// every byte is decoded, operands as the content of their opcode
+pub fn get_operations(module: &format::Module) -> Vec<Operation> {
+  let bytes = &module.code;
   let mut program = Vec::new();
   let mut i = 0;
   let blen = bytes.len();
@@ -143,66 +222,31 @@ pub fn get_program(bytes: Vec<u8>) -> Program {
     let op = bytes[i];
     let code = OPCode::from_i32(op as i32);
 
-    let mut content = unsafe {
-      match code {
-        Some(val) => match val {
-          PUSH_NUM => {
-            if i + 8 < blen {
-              let mut content_vec: [u8; 8] = [0x00; 8];
-              for j in 0..8 {
-                let op = bytes[i+j+1];
-                content_vec[j] = op;
-              }
-              OperationLiteral::Num(mem::transmute::<[u8; 8], f64>(content_vec)) 
-            } else {
-              OperationLiteral::None
-            }
-          },
-          STRING | PUSH_STRING | PUSH_VAR => {
-            let mut content_vec: Vec<u8> = Vec::new();
-            let mut j = 0;
-            //let content = OperationLiteral::None;
-            let mut is_invalid = false;
-            loop {
-              if i + j + 1 > blen { // invalid
-                is_invalid = true;
-                break;
-              }
-              let op = bytes[i+j+1];
-              if op == u(NULL) {
-                break;
-              }
-              content_vec.push(op);
-              j += 1;
-            }
-
-            if !is_invalid {
-              let s = String::from(match str::from_utf8(&content_vec) {
-                Ok(v) => v,
-                Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-              });
-              let len = s.len() + 1; // + 1 null terminator
-              OperationLiteral::String(s, len)
-            } else {
-              OperationLiteral::None
-            }
-          },
-          I32 | PUSH_INT | PUSH_JUMP => {
-            if i + 4 < blen {
-              let mut content_vec: [u8; 4] = [0x00; 4];
-              for j in 0..4 {
-                let op = bytes[i+j+1];
-                content_vec[j] = op;
-              }
-              OperationLiteral::Int(mem::transmute::<[u8; 4], i32>(content_vec))
-            } else {
-              OperationLiteral::None
-            }
-          },
-          _ => OperationLiteral::None
+    let mut content = match code {
+      Some(val) => match val {
+        PUSH_CONST | PUSH_VAR | STRING => {
+          // the content is an index, resolved so every module can be executed on its own
+          match get_i32(bytes, i) {
+            Some(index) if val == PUSH_CONST => match module.constants.get(index as usize) {
+              Some(&Constant::Num(num)) => OperationLiteral::Num(num),
+              Some(&Constant::Int(int)) => OperationLiteral::Int64(int),
+              Some(&Constant::String(ref s)) => OperationLiteral::String(s.to_owned(), 4),
+              None => OperationLiteral::None
+            },
+            Some(index) => match module.symbols.get(index as usize) {
+              Some(name) => OperationLiteral::String(name.to_owned(), 4),
+              None => OperationLiteral::None
+            },
+            None => OperationLiteral::None
+          }
+        },
+        I32 | PUSH_JUMP | PUSH_LOOP => match get_i32(bytes, i) {
+          Some(int) => OperationLiteral::Int(int),
+          None => OperationLiteral::None
         },
-        None => OperationLiteral::None
-      }
+        _ => OperationLiteral::None
+      },
+      None => OperationLiteral::None
     };
     program.push(Operation {
       code,
@@ -217,7 +261,8 @@ pub fn get_program(bytes: Vec<u8>) -> Program {
 
 pub struct VM {
   pub vm_exec: VMExec,
-  vm_build: VMBuild
+  vm_build: VMBuild,
+  pub error_format: ErrorFormat
 }
 
 impl VM {
@@ -227,16 +272,17 @@ impl VM {
 
     Self {
       vm_exec: VMExec::new(),
-      vm_build: VMBuild::new()
+      vm_build: VMBuild::new(),
+      error_format: ErrorFormat::Text
     }
   }
 
-  pub fn get_query(self) -> String {
-    self.vm_exec.query
+  pub fn get_sources(self) -> Vec<Source> {
+    self.vm_exec.sources
   }
 
-  pub fn build(&mut self, decls: Decls, code: String, debug_offset: usize, options: BuildOptions) -> Result<Instructions, VMBuildError> {
-    self.vm_build.build(decls, code, debug_offset, options)
+  pub fn build(&mut self, decls: Decls, source: Source, span_offset: usize, options: BuildOptions) -> Result<Instructions, VMBuildError> {
+    self.vm_build.build(decls, source, span_offset, options)
   }
 
   pub fn exec(&mut self, mut program: Program, append: bool) -> Result<String, VMExecError> {
@@ -1,18 +1,24 @@
 use std::io;
 use std::io::{Write};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-use vm::exec::{VMExecError, Value, Literal, Function};
+use vm::NIL;
+use vm::OPCode;
+use vm::exec::{VMExecError, Value, Literal, Function, Array, Map, Root};
 use vm::cast::{NativeScope, NativePars, NativeReturn};
 
 fn literal_to_string(literal: &Literal, quotes: bool) -> String {
   let quotes = if quotes {"\""} else {""};
   match literal {
     &Literal::Num(val) => format!("{}", val),
+    &Literal::Int(val) => format!("{}", val),
     &Literal::Nil => format!("nil"),
     &Literal::Bool(b) => format!("{}", if b {"true"} else {"false"}),
+    &Literal::Range(start, end) => format!("{}..{}", start, end),
     &Literal::String(ref val) => format!("{}{}{}", quotes, val, quotes),
     &Literal::Function(ref func_type) => match func_type {
-      &Function::InCode(pos, ref arguments) => {
+      &Function::InCode(pos, ref arguments, _) => {
         let mut args = String::new();
         let mut first = true;
         for i in arguments {
@@ -36,11 +42,41 @@ fn non_literal_to_string(scope: NativeScope, val: *const Value, quotes: bool) ->
   unsafe {
       Ok(match &*val {
       &Value::Instance(scope) => format!("<instance at {:?}>", scope),
+      &Value::Method(instance, func) => format!("<bound {} of <instance at {:?}>>", value_to_string(scope, func, quotes)?, instance),
+      &Value::Array(_) | &Value::Map(_) => collection_to_string(scope, val, &mut Vec::new())?,
       _ => format!("unknown value")
     })
   }
 }
 
+// parents are the collections currently being printed, so collections containing themselves don't recurse forever
+fn collection_to_string(scope: NativeScope, val: *const Value, parents: &mut Vec<*const ()>) -> Result<String, VMExecError> {
+  let (id, open, close) = match unsafe { &*val } {
+    &Value::Array(ref elements) => (Rc::as_ptr(elements) as *const (), "[", "]"),
+    &Value::Map(ref entries) => (Rc::as_ptr(entries) as *const (), "{", "}"),
+    _ => return value_to_string(scope, val, true)
+  };
+
+  if parents.contains(&id) {
+    return Ok(format!("{}...{}", open, close));
+  }
+  parents.push(id);
+
+  let mut items = Vec::new();
+  match unsafe { &*val } {
+    &Value::Array(ref elements) => for i in elements.borrow().iter() {
+      items.push(collection_to_string(scope, *i, parents)?);
+    },
+    &Value::Map(ref entries) => for (k, v) in entries.borrow().iter() {
+      items.push(format!("\"{}\": {}", k, collection_to_string(scope, *v, parents)?));
+    },
+    _ => {}
+  }
+
+  parents.pop();
+  Ok(format!("{}{}{}", open, items.join(", "), close))
+}
+
 pub fn value_to_string(scope: NativeScope, val: *const Value, quotes: bool) -> Result<String, VMExecError> {
   unsafe {
     Ok(match *val {
@@ -55,15 +91,12 @@ pub fn value_to_string(scope: NativeScope, val: *const Value, quotes: bool) -> R
           None => 0
         }))
       },
-      Value::Pointer(ref identifier, pos, scope) => match (*scope).get_var(identifier) {
-        Some(val) => match *val {
+      Value::Pointer(ref identifier, pos, scope) => {
+        let val = (*scope).get_field(identifier, pos)?;
+        match *val {
           Value::Literal(ref val) => literal_to_string(val, quotes),
           _ => non_literal_to_string(scope, val, quotes)?
-        },
-        None => return Err(VMExecError::VariableNotDefined(identifier.to_string(), match pos {
-          Some(pos) => pos,
-          None => 0
-        }))
+        }
       },
       _ => non_literal_to_string(scope, val, quotes)?
     })
@@ -82,6 +115,10 @@ pub fn add_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
     match (&*first, &*second) {
       (&Value::Literal(ref first), &Value::Literal(ref second)) => match (first, second) {
         (&Literal::Num(first), &Literal::Num(second)) => Ok(Some(Value::Literal(Literal::Num(first + second)))),
+        (&Literal::Int(first), &Literal::Int(second)) => match first.checked_add(second) {
+          Some(sum) => Ok(Some(Value::Literal(Literal::Int(sum)))),
+          None => Err(VMExecError::IntegerOverflow(OPCode::ADD, -1))
+        },
         (_, _) => Ok(None)
       }
       (_, _) => Ok(None)
@@ -116,4 +153,156 @@ pub fn input_func(_scope: NativeScope, _args: NativePars) -> NativeReturn {
   query.pop().unwrap();
 
   Ok(Some(Value::Literal(Literal::String(query))))
-}
\ No newline at end of file
+}
+
+pub fn gc_func(scope: NativeScope, _args: NativePars) -> NativeReturn {
+  get_root(scope).request_gc();
+  Ok(None)
+}
+
+/// The scope handed to a native is always a live scope of the running VM.
+fn get_root<'a>(scope: NativeScope) -> &'a mut Root {
+  unsafe {
+    &mut *(*scope).get_root()
+  }
+}
+
+fn get_arg(args: &NativePars, i: usize) -> *const Value {
+  match args.get(i) {
+    Some(val) => *val,
+    None => NIL
+  }
+}
+
+fn get_array<'a>(val: *const Value) -> Result<&'a Array, VMExecError> {
+  unsafe {
+    match &*val {
+      &Value::Array(ref elements) => Ok(elements),
+      val => Err(VMExecError::InvalidCast(val.clone(), "<array>".to_string(), None))
+    }
+  }
+}
+
+fn get_map<'a>(val: *const Value) -> Result<&'a Map, VMExecError> {
+  unsafe {
+    match &*val {
+      &Value::Map(ref entries) => Ok(entries),
+      val => Err(VMExecError::InvalidCast(val.clone(), "<map>".to_string(), None))
+    }
+  }
+}
+
+fn get_key(val: *const Value) -> Result<String, VMExecError> {
+  unsafe {
+    match &*val {
+      &Value::Literal(Literal::String(ref key)) => Ok(key.to_string()),
+      val => Err(VMExecError::InvalidCast(val.clone(), "<string>".to_string(), None))
+    }
+  }
+}
+
+fn get_int(val: *const Value) -> Result<i64, VMExecError> {
+  unsafe {
+    match &*val {
+      &Value::Literal(Literal::Num(num)) if num.fract() == 0.0 => Ok(num as i64),
+      &Value::Literal(Literal::Int(int)) => Ok(int),
+      val => Err(VMExecError::InvalidCast(val.clone(), "<int>".to_string(), None))
+    }
+  }
+}
+
+pub fn len_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let val = get_arg(&args, 0);
+  let len = unsafe {
+    match &*val {
+      &Value::Literal(Literal::String(ref s)) => s.chars().count(),
+      &Value::Map(ref entries) => entries.borrow().len(),
+      _ => get_array(val)?.borrow().len()
+    }
+  };
+
+  Ok(Some(Value::Literal(Literal::Int(len as i64))))
+}
+
+pub fn push_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let array = get_array(get_arg(&args, 0))?;
+  for i in args.iter().skip(1) {
+    array.borrow_mut().push(*i);
+  }
+
+  Ok(None)
+}
+
+pub fn pop_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let array = get_array(get_arg(&args, 0))?;
+  let val = match array.borrow_mut().pop() {
+    Some(val) => val,
+    None => NIL
+  };
+
+  unsafe {
+    Ok(Some((*val).clone()))
+  }
+}
+
+/// `slice(array, start, end)`, `end` defaults to the length of the array.
+pub fn slice_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let array = get_array(get_arg(&args, 0))?.borrow();
+  let len = array.len();
+
+  let start = get_int(get_arg(&args, 1))?;
+  let end = match args.get(2) {
+    Some(end) => get_int(*end)?,
+    None => len as i64
+  };
+
+  if start < 0 || start as usize > len {
+    return Err(VMExecError::IndexOutOfBounds(start, len, None));
+  }
+  if end < start || end as usize > len {
+    return Err(VMExecError::IndexOutOfBounds(end, len, None));
+  }
+
+  let elements = array[start as usize..end as usize].to_vec();
+  Ok(Some(Value::Array(Rc::new(RefCell::new(elements)))))
+}
+
+pub fn keys_func(scope: NativeScope, args: NativePars) -> NativeReturn {
+  let map = get_map(get_arg(&args, 0))?;
+  let root = get_root(scope);
+
+  let keys = map.borrow().keys()
+    .map(|key| root.alloc(Value::Literal(Literal::String(key.to_string()))))
+    .collect();
+
+  Ok(Some(Value::Array(Rc::new(RefCell::new(keys)))))
+}
+
+pub fn values_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let map = get_map(get_arg(&args, 0))?;
+  let values = map.borrow().values().cloned().collect();
+
+  Ok(Some(Value::Array(Rc::new(RefCell::new(values)))))
+}
+
+pub fn has_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let map = get_map(get_arg(&args, 0))?;
+  let key = get_key(get_arg(&args, 1))?;
+
+  Ok(Some(Value::Literal(Literal::Bool(map.borrow().contains_key(&key)))))
+}
+
+/// Removes the key from the map and returns its value, nil if it wasn't there.
+pub fn remove_func(_scope: NativeScope, args: NativePars) -> NativeReturn {
+  let map = get_map(get_arg(&args, 0))?;
+  let key = get_key(get_arg(&args, 1))?;
+
+  let val = match map.borrow_mut().remove(&key) {
+    Some(val) => val,
+    None => NIL
+  };
+
+  unsafe {
+    Ok(Some((*val).clone()))
+  }
+}
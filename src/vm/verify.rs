@@ -0,0 +1,230 @@
+//! Checks that a decoded program is well formed before it's executed,
+//! so a corrupt or truncated `.lby` results in an error instead of a crash.
+
+use std::collections::HashSet;
+
+use vm::{Program, Operation, OperationLiteral, OPCode};
+use vm::OPCode::*;
+
+#[derive(Debug)]
+pub enum VerifyError {
+  MissingEnd,
+
+  // position in bytecode
+  UnknownOPCode(u8, usize),
+
+  // opcode, position of the instruction
+  UnexpectedOPCode(OPCode, usize),
+  MissingOperand(OPCode, usize),
+  InvalidOperand(OPCode, usize),
+
+  // position of the instruction, target
+  InvalidJump(usize, i64)
+}
+
+#[derive(Debug)]
+pub enum Operand {
+  Int(i32),
+  Str(String),
+  Num(f64),
+  Int64(i64),
+  Bool(bool)
+}
+
+/// How an operand of an instruction should be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+  Pos, // debug position, only emitted in debug builds
+  Jump, // relative to the last byte of the instruction
+  Int,
+  Str,
+  Literal // content stored directly after the opcode
+}
+
+#[derive(Debug)]
+pub struct Instruction {
+  pub code: OPCode,
+  pub start: usize,
+  pub end: usize, // last byte
+  pub operands: Vec<(Kind, Operand)>
+}
+
+impl Instruction {
+  /// Absolute positions execution can continue at through this instruction's jump operands.
+  pub fn jump_targets(&self) -> Vec<i64> {
+    self.operands.iter()
+      .filter_map(|operand| match *operand {
+        (Kind::Jump, Operand::Int(offset)) => Some(self.end as i64 + offset as i64 + 1),
+        _ => None
+      })
+      .collect()
+  }
+}
+
+// the I32 and STRING operands following the opcode
+fn layout(code: OPCode) -> &'static [Kind] {
+  use self::Kind::*;
+
+  match code {
+    PUSH_VAR | CALL_FUNC_STACK_ARGS | CALL_STRUCT | GET_SCOPE | ITER_RESULT |
+    GET_INDEX | SET_INDEX | NOT | RETURN | CONTINUE | EXTEND => &[Pos],
+    ADD | SUB | MULTIPLY | ASSIGN | LET | DIVIDE | INT_DIVIDE | MOD |
+    GT | LT | GTOREQ | LTOREQ | EQ | NEQ | RANGE |
+    POW | BIT_AND | BIT_OR | BIT_XOR | SHL | SHR => &[Pos],
+    PUSH_POINTER => &[Str, Pos],
+    PUSH_FUNC => &[Pos, Jump, Int], // followed by as many parameter names
+    CALL_FUNC | PUSH_ARRAY | PUSH_MAP => &[Pos, Int],
+    PUSH_STRUCT | ITER | ITER_CHECK | JUMPIFN => &[Pos, Jump],
+    ITER_NEXT => &[Pos, Jump, Jump],
+    JUMP => &[Jump],
+    BREAK | DUP => &[Int],
+    DECLARE => &[Str],
+    _ => &[]
+  }
+}
+
+/// Reads the instruction starting at `start`, with all of its operands.
+pub fn read_instruction(program: &[Operation], start: usize, is_debug: bool) -> Result<Instruction, VerifyError> {
+  let len = program.len();
+  let op = &program[start];
+  let code = match op.code {
+    Some(code) => code,
+    None => return Err(VerifyError::UnknownOPCode(op.val, start))
+  };
+
+  let mut operands = Vec::new();
+  let mut i = start + 1;
+
+  let invalid = || VerifyError::InvalidOperand(code, start);
+
+  match code {
+    NULL | VERSION | DEBUG | META_END | DEBUG_CODE | DEBUG_CODE_END | I32 | STRING |
+    PUSH_NUM | PUSH_INT | PUSH_STRING => {
+      return Err(VerifyError::UnexpectedOPCode(code, start));
+    },
+    PUSH_CONST => {
+      operands.push((Kind::Literal, match op.content {
+        OperationLiteral::Num(num) => Operand::Num(num),
+        OperationLiteral::Int64(int) => Operand::Int64(int),
+        OperationLiteral::String(ref s, _) => Operand::Str(s.to_owned()),
+        _ => return Err(invalid())
+      }));
+      i += 4;
+    },
+    PUSH_JUMP | PUSH_LOOP => match op.content {
+      OperationLiteral::Int(int) => {
+        operands.push((if code == PUSH_LOOP { Kind::Jump } else { Kind::Literal }, Operand::Int(int)));
+        i += 4;
+      },
+      _ => return Err(invalid())
+    },
+    PUSH_VAR => match op.content {
+      OperationLiteral::String(ref s, s_len) => {
+        operands.push((Kind::Literal, Operand::Str(s.to_owned())));
+        i += s_len;
+      },
+      _ => return Err(invalid())
+    },
+    PUSH_BOOL => {
+      if i >= len {
+        return Err(invalid());
+      }
+      operands.push((Kind::Literal, Operand::Bool(program[i].val != 0)));
+      i += 1;
+    },
+    _ => {}
+  }
+
+  let mut kinds: Vec<Kind> = layout(code).iter()
+    .cloned()
+    .filter(|kind| is_debug || *kind != Kind::Pos)
+    .collect();
+
+  let mut k = 0;
+  while k < kinds.len() {
+    let kind = kinds[k];
+    if i >= len {
+      return Err(VerifyError::MissingOperand(code, start));
+    }
+
+    let operand = match (kind, program[i].code, &program[i].content) {
+      (Kind::Str, Some(STRING), &OperationLiteral::String(ref s, s_len)) => {
+        i += 1 + s_len;
+        Operand::Str(s.to_owned())
+      },
+      (Kind::Str, Some(I32), _) | (_, Some(STRING), _) => return Err(invalid()),
+      (_, Some(I32), &OperationLiteral::Int(int)) => {
+        i += 1 + 4;
+        Operand::Int(int)
+      },
+      (_, Some(I32), _) => return Err(invalid()),
+      _ => return Err(VerifyError::MissingOperand(code, start))
+    };
+
+    // counts of values popped from the stack
+    match (code, kind, &operand) {
+      (CALL_FUNC, Kind::Int, &Operand::Int(count)) |
+      (PUSH_ARRAY, Kind::Int, &Operand::Int(count)) |
+      (PUSH_MAP, Kind::Int, &Operand::Int(count)) |
+      (BREAK, Kind::Int, &Operand::Int(count)) |
+      (DUP, Kind::Int, &Operand::Int(count)) if count < 0 => return Err(invalid()),
+      _ => {}
+    }
+
+    // the parameter count of a function is followed by the parameter names, at least 5 bytes each
+    if let (PUSH_FUNC, Kind::Int, &Operand::Int(par_len)) = (code, kind, &operand) {
+      if par_len < 0 || par_len as usize > len.saturating_sub(i) / 5 {
+        return Err(invalid());
+      }
+      for _ in 0..par_len {
+        kinds.push(Kind::Str);
+      }
+    }
+
+    operands.push((kind, operand));
+    k += 1;
+  }
+
+  if i > len {
+    return Err(invalid());
+  }
+
+  Ok(Instruction {
+    code,
+    start,
+    end: i - 1,
+    operands
+  })
+}
+
+/// Checks that every instruction is known and complete,
+/// that jumps land on instructions and that the program ends with `END`.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+  let operations = &program.operations;
+  let is_debug = program.module.is_debug;
+
+  let mut starts = HashSet::new();
+  let mut instructions = Vec::new();
+  let mut i = 0;
+  while i < operations.len() {
+    let instruction = read_instruction(operations, i, is_debug)?;
+    starts.insert(instruction.start as i64);
+    i = instruction.end + 1;
+    instructions.push(instruction);
+  }
+
+  match instructions.last() {
+    Some(instruction) if instruction.code == END => {},
+    _ => return Err(VerifyError::MissingEnd)
+  }
+
+  for instruction in &instructions {
+    for target in instruction.jump_targets() {
+      if !starts.contains(&target) {
+        return Err(VerifyError::InvalidJump(instruction.start, target));
+      }
+    }
+  }
+
+  Ok(())
+}